@@ -4,12 +4,15 @@ extern crate liblightning;
 extern crate test;
 
 use std::cell::Cell;
-use std::sync::Arc;
+use std::rc::Rc;
+use std::thread;
+use std::sync::{Arc, Mutex, Barrier};
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
 use std::panic::resume_unwind;
 use liblightning::{CoState, Stack, StackPool, StackPoolConfig, Promise, Scheduler};
 use liblightning::co::CommonCoState;
+use liblightning::promise::SendableNotifyHandle;
 use test::Bencher;
 
 #[bench]
@@ -96,3 +99,70 @@ fn bench_sched_async(b: &mut Bencher) {
     });
     sched.run_value_promise_to_end(vp).unwrap();
 }
+
+#[bench]
+fn bench_sched_multi_producer_notify(b: &mut Bencher) {
+    const PRODUCERS: usize = 8;
+    const COROUTINES: usize = 256;
+
+    let mut sched = Scheduler::new_default();
+    let state = sched.get_state();
+
+    // Handles collected from parked coroutines, one batch per producer.
+    let batches: Arc<Vec<Mutex<Vec<SendableNotifyHandle>>>> = Arc::new(
+        (0..PRODUCERS).map(|_| Mutex::new(Vec::new())).collect()
+    );
+    let start = Arc::new(Barrier::new(PRODUCERS + 1));
+    let stop = Arc::new(AtomicBool::new(false));
+
+    // Each producer thread notifies all handles of its batch at once.
+    let producers: Vec<thread::JoinHandle<()>> = (0..PRODUCERS).map(|i| {
+        let batches = batches.clone();
+        let start = start.clone();
+        let stop = stop.clone();
+        thread::spawn(move || {
+            loop {
+                start.wait();
+                if stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                let batch: Vec<SendableNotifyHandle> = batches[i].lock().unwrap().drain(..).collect();
+                for h in batch {
+                    h.notify();
+                }
+            }
+        })
+    }).collect();
+
+    b.iter(|| {
+        let done: Rc<Cell<usize>> = Rc::new(Cell::new(0));
+        for i in 0..COROUTINES {
+            let batches = batches.clone();
+            let done = done.clone();
+            state.start_coroutine(move |c| {
+                c.yield_now(&Promise::new(move |h| {
+                    batches[i % PRODUCERS].lock().unwrap().push(h.into_sendable());
+                }));
+                done.set(done.get() + 1);
+            });
+        }
+
+        let start = start.clone();
+        let vp = state.prepare_coroutine(move |c| {
+            // All coroutines above park before this one runs again.
+            c.yield_now(&Promise::new_started());
+            start.wait();
+
+            while done.get() < COROUTINES {
+                c.yield_now(&Promise::new_started());
+            }
+        });
+        sched.run_value_promise_to_end(vp).unwrap();
+    });
+
+    stop.store(true, Ordering::SeqCst);
+    start.wait();
+    for t in producers {
+        t.join().unwrap();
+    }
+}
@@ -44,7 +44,8 @@ pub extern "C" fn ll_scheduler_new_with_config(stack_size: usize, max_pool_size:
     Box::into_raw(Box::new(Scheduler::new(SchedulerConfig {
         stack_pool: StackPool::new(StackPoolConfig {
             default_stack_size: stack_size,
-            max_pool_size: max_pool_size
+            max_pool_size: max_pool_size,
+            ..StackPoolConfig::default()
         })
     })))
 }
@@ -11,12 +11,20 @@ impl SetupForPlatform for cc::Build {
             | "x86_64-unknown-linux-musl" => {
                 self.file("platforms/x86_64-linux/sched_helper.s")
             },
+            "aarch64-unknown-linux-gnu"
+            | "aarch64-unknown-linux-musl" => {
+                self.file("platforms/aarch64-linux/sched_helper.s")
+            },
             _ => panic!("Unsupported platform: {}", platform)
         }
     }
 }
 
 fn main() {
+    // cc emits its own rerun-if directives, which disable the default
+    // "rerun on any change" behavior.
+    println!("cargo:rerun-if-changed=platforms");
+
     cc::Build::new()
         .setup_for_platform(std::env::var("TARGET").unwrap_or_else(|e| {
             panic!("Error while reading the TARGET environment variable: {:?}", e);
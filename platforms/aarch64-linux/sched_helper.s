@@ -0,0 +1,66 @@
+// Callee-saved state: x19-x28, x29 (fp), x30 (lr) and d8-d15.
+// 160 bytes in total, which keeps SP 16-byte aligned.
+
+.macro save_stack_state
+    sub sp, sp, #160
+    stp x19, x20, [sp, #0]
+    stp x21, x22, [sp, #16]
+    stp x23, x24, [sp, #32]
+    stp x25, x26, [sp, #48]
+    stp x27, x28, [sp, #64]
+    stp x29, x30, [sp, #80]
+    stp d8, d9, [sp, #96]
+    stp d10, d11, [sp, #112]
+    stp d12, d13, [sp, #128]
+    stp d14, d15, [sp, #144]
+.endm
+
+.macro restore_stack_state
+    ldp x19, x20, [sp, #0]
+    ldp x21, x22, [sp, #16]
+    ldp x23, x24, [sp, #32]
+    ldp x25, x26, [sp, #48]
+    ldp x27, x28, [sp, #64]
+    ldp x29, x30, [sp, #80]
+    ldp d8, d9, [sp, #96]
+    ldp d10, d11, [sp, #112]
+    ldp d12, d13, [sp, #128]
+    ldp d14, d15, [sp, #144]
+    add sp, sp, #160
+.endm
+
+.text
+
+.globl __ll_co_yield_now
+.type __ll_co_yield_now, %function
+.p2align 2
+__ll_co_yield_now:
+    save_stack_state
+    mov x9, sp
+    str x9, [x0] // Store the old SP into (x0)
+    mov sp, x1 // Load the new SP
+    // We are now on the target stack.
+    // We assume that the target stack is yielded previously.
+    restore_stack_state
+    ret
+.size __ll_co_yield_now, .-__ll_co_yield_now
+
+.globl __ll_init_co_stack
+.type __ll_init_co_stack, %function
+.p2align 2
+__ll_init_co_stack:
+    save_stack_state
+    mov x9, sp
+    str x9, [x0] // Store the old SP into (x0)
+    mov sp, x1 // Load the new SP
+    // We are now on the target stack.
+    // Nothing is on the stack now. Terminate the frame chain here.
+    mov x29, #0
+    mov x30, #0
+    mov x0, x3 // user_data
+
+    blr x2 // The initialization function.
+    bl abort
+.size __ll_init_co_stack, .-__ll_init_co_stack
+
+.section .note.GNU-stack,"",%progbits
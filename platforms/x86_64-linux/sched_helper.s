@@ -5,6 +5,10 @@ save_stack_state:
     pushq %r13
     pushq %r14
     pushq %r15
+    # Floating-point control state (callee-saved in the SysV ABI).
+    subq $8, %rsp
+    stmxcsr (%rsp)
+    fnstcw 4(%rsp)
     movq %rsp, %rax
     jmp *%rdi
 
@@ -18,6 +22,9 @@ __ll_co_yield_now:
     movq %rsi, %rsp # Load the new RSP
     # We are now on the target stack.
     # We assume that the target stack is yielded previously.
+    ldmxcsr (%rsp)
+    fldcw 4(%rsp)
+    addq $8, %rsp
     popq %r15
     popq %r14
     popq %r13
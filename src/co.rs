@@ -4,12 +4,14 @@ use std::os::raw;
 use std::any::Any;
 use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
 use promise::Promise;
+use mpsc_queue::CoBox;
+use scheduler::SyncSchedState;
 
 pub type StackInitializer = extern "C" fn (user_data: *mut raw::c_void);
 
 extern "C" {
-    fn __ll_co_yield_now(rsp_save_target: *mut usize, new_rsp: usize);
-    fn __ll_init_co_stack(
+    pub(crate) fn __ll_co_yield_now(rsp_save_target: *mut usize, new_rsp: usize);
+    pub(crate) fn __ll_init_co_stack(
         rsp_save_target: *mut usize,
         new_rsp: usize,
         initializer: StackInitializer,
@@ -24,47 +26,234 @@ enum RunningState {
     Terminated
 }
 
+// The values passed across a context switch.
 struct MaybeYieldVal {
-    val: Option<*const Promise>
+    // Set by the coroutine when it yields.
+    val: Option<*const Promise>,
+
+    // Set by the resumer of a duplex coroutine. Points to an `Option<I>`
+    // on the resumer's stack that is valid until the switch returns.
+    input: Option<*mut raw::c_void>
 }
 
 unsafe impl Send for MaybeYieldVal {}
 
+/// The context-switching state shared by the coroutine implementations.
+pub(crate) struct CoContext<F> {
+    // The saved stack pointer of whichever side is not running.
+    pub(crate) rsp: usize,
+    yield_val: MaybeYieldVal,
+    error_val: Option<Box<Any + Send>>,
+    running_state: RunningState,
+    f: Option<F>
+}
+
+/// A coroutine type built on `CoContext`.
+pub(crate) trait HasCoContext: Sized {
+    type F;
+
+    fn context(&mut self) -> &mut CoContext<Self::F>;
+
+    /// Runs the coroutine function. Called on the coroutine stack.
+    fn call(&mut self, f: Self::F);
+}
+
+impl<F> CoContext<F> {
+    pub(crate) fn new(initial_rsp: usize, f: F) -> CoContext<F> {
+        CoContext {
+            rsp: initial_rsp,
+            yield_val: MaybeYieldVal { val: None, input: None },
+            error_val: None,
+            running_state: RunningState::NotStarted,
+            f: Some(f)
+        }
+    }
+
+    pub(crate) fn is_started(&self) -> bool {
+        self.running_state != RunningState::NotStarted
+    }
+
+    pub(crate) fn is_terminated(&self) -> bool {
+        self.running_state == RunningState::Terminated
+    }
+
+    /// Switches back to the resumer. Called from inside the coroutine.
+    pub(crate) fn yield_now(&mut self, val: &Promise) {
+        unsafe {
+            self.yield_val.val = Some(val as *const Promise);
+
+            let new_rsp = self.rsp;
+            __ll_co_yield_now(&mut self.rsp, new_rsp);
+        }
+    }
+
+    /// Switches into the coroutine owning this context until it yields or
+    /// terminates. A coroutine that is not started yet starts at `initial_rsp`.
+    ///
+    /// `owner` must point to the coroutine owning this context.
+    pub(crate) unsafe fn switch_in<T: HasCoContext<F = F>>(&mut self, owner: *mut T, initial_rsp: usize) {
+        let new_rsp = self.rsp;
+
+        match self.running_state {
+            RunningState::NotStarted => {
+                self.running_state = RunningState::Running;
+                let rsp = &mut self.rsp as *mut usize;
+                __ll_init_co_stack(rsp, initial_rsp, co_initializer::<T>, owner as *mut raw::c_void);
+            },
+            RunningState::Running => {
+                __ll_co_yield_now(&mut self.rsp, new_rsp);
+            },
+            RunningState::Terminated => {}
+        }
+    }
+
+    /// Like `switch_in`, but makes `input` available to `take_input` inside
+    /// the coroutine.
+    pub(crate) unsafe fn switch_in_with<T: HasCoContext<F = F>, I>(&mut self, owner: *mut T, initial_rsp: usize, input: I) {
+        let mut input = Some(input);
+        self.yield_val.input = Some(&mut input as *mut Option<I> as *mut raw::c_void);
+        self.switch_in(owner, initial_rsp);
+        self.yield_val.input = None;
+    }
+
+    /// Takes the value passed to the `switch_in_with` that resumed the
+    /// coroutine. Called from inside the coroutine.
+    ///
+    /// `I` must be the type passed to `switch_in_with`.
+    pub(crate) unsafe fn take_input<I>(&mut self) -> Option<I> {
+        match self.yield_val.input.take() {
+            Some(p) => (*(p as *mut Option<I>)).take(),
+            None => None
+        }
+    }
+
+    /// Takes the promise yielded by the last switch, if any. A panic of the
+    /// coroutine is propagated.
+    pub(crate) fn take_yield_val(&mut self) -> Option<*const Promise> {
+        if let Some(e) = self.error_val.take() {
+            resume_unwind(e);
+        }
+
+        self.yield_val.val.take()
+    }
+
+    unsafe fn terminate_from_inside(&mut self) -> ! {
+        self.running_state = RunningState::Terminated;
+
+        self.yield_val.val = None;
+
+        let new_rsp = self.rsp;
+        __ll_co_yield_now(&mut self.rsp, new_rsp);
+
+        eprintln!("Coroutine termination failed");
+        ::std::process::abort();
+    }
+
+    pub(crate) fn ensure_terminated(&self) {
+        if self.running_state != RunningState::Terminated {
+            panic!("The current coroutine is required to be terminated at this point");
+        }
+    }
+}
+
+extern "C" fn co_initializer<T: HasCoContext>(user_data: *mut raw::c_void) {
+    let this: &mut T = unsafe { &mut *(user_data as *mut T) };
+    {
+        let f = this.context().f.take().unwrap();
+        if let Err(e) = catch_unwind(AssertUnwindSafe(|| this.call(f))) {
+            this.context().error_val = Some(e);
+        }
+    }
+
+    // No droppable objects should remain at this point.
+    // Otherwise there will be a resource leak.
+    unsafe {
+        this.context().terminate_from_inside();
+    }
+}
+
 pub trait CommonCoState {
     fn resume(&mut self) -> Option<&Promise>;
     fn take_stack(&mut self) -> Option<Stack>;
+
+    /// Returns whether the coroutine may be resumed on another thread.
+    ///
+    /// Only coroutines created from a `Send` closure are migratable.
+    fn is_migratable(&self) -> bool {
+        false
+    }
 }
+
 /// The state of a coroutine.
 ///
 /// Must not be accessed by the coroutine itself.
 pub struct CoState<F: FnOnce(&mut Yieldable) + 'static> {
     stack: Option<Stack>,
-    rsp: usize,
-    yield_val: MaybeYieldVal,
-    error_val: Option<Box<Any + Send>>,
-    running_state: RunningState,
-    f: Option<F>
+    ctx: CoContext<F>,
+    migratable: bool
 }
 
-/// Used for returning from a promise execution on another thread.
+/// A suspended coroutine on its way to a scheduler on another thread.
+///
+/// A coroutine that is not migratable is bound to its `home` scheduler,
+/// which is the only one allowed to take it back.
 pub(crate) struct SendableCoState {
-    inner: Box<CommonCoState>
+    inner: CoBox,
+    home: Option<SyncSchedState>
 }
 
+// Only migratable coroutines are resumed on another thread.
 unsafe impl Send for SendableCoState {}
 
 impl SendableCoState {
-    pub fn new(inner: Box<CommonCoState>) -> SendableCoState {
+    /// Binds `inner` to `home`, the scheduler it was suspended on.
+    pub fn new(inner: CoBox, home: SyncSchedState) -> SendableCoState {
         SendableCoState {
-            inner: inner
+            inner: inner,
+            home: Some(home)
+        }
+    }
+
+    /// Detaches `inner` from its scheduler, or gives it back if it is
+    /// not migratable.
+    pub fn new_migratable(inner: CoBox) -> Result<SendableCoState, CoBox> {
+        if !inner.is_migratable() {
+            return Err(inner);
+        }
+        Ok(SendableCoState {
+            inner: inner,
+            home: None
+        })
+    }
+
+    /// Returns whether the coroutine may be resumed by `target`.
+    pub fn can_run_on(&self, target: &SyncSchedState) -> bool {
+        match self.home {
+            Some(ref home) => home.same_as(target),
+            None => true
         }
     }
 
-    pub unsafe fn unwrap(self) -> Box<CommonCoState> {
+    /// Must only be called on a thread the coroutine can run on.
+    pub unsafe fn unwrap(self) -> CoBox {
         self.inner
     }
 }
 
+fn stack_begin(stack: &Stack) -> *mut u8 {
+    unsafe {
+        let mem = &mut *stack.get_mem();
+        (&mut mem[0] as *mut u8).offset(mem.len() as isize)
+    }
+}
+
+fn stack_end(stack: &Stack) -> *mut u8 {
+    unsafe {
+        let mem = &mut *stack.get_mem();
+        &mut mem[0] as *mut u8
+    }
+}
+
 /// A coroutine's view of itself.
 ///
 /// Only accessible from inside a coroutine.
@@ -72,72 +261,74 @@ pub trait Yieldable {
     fn yield_now(&mut self, val: &Promise);
     fn stack_begin(&self) -> *mut u8;
     fn stack_end(&self) -> *mut u8;
+
+    /// Returns the peak usage of the coroutine's stack so far, or `None`
+    /// if the stack is not measured.
+    fn stack_peak_usage(&self) -> Option<usize> {
+        None
+    }
 }
 
 impl<F: FnOnce(&mut Yieldable) + 'static> Yieldable for CoState<F> {
     fn yield_now(&mut self, val: &Promise) {
-        unsafe {
-            self.yield_val = MaybeYieldVal { val: Some(val as *const Promise) };
-
-            let new_rsp = self.rsp;
-            __ll_co_yield_now(&mut self.rsp, new_rsp);
-        }
+        self.ctx.yield_now(val);
     }
 
     fn stack_begin(&self) -> *mut u8 {
-        unsafe {
-            let stack = self.stack.as_ref().unwrap();
-            let mem = &mut *stack.get_mem();
-            (&mut mem[0] as *mut u8).offset(mem.len() as isize)
-        }
+        stack_begin(self.stack.as_ref().unwrap())
     }
 
     fn stack_end(&self) -> *mut u8 {
-        unsafe {
-            let stack = self.stack.as_ref().unwrap();
-            let mem = &mut *stack.get_mem();
-            &mut mem[0] as *mut u8
-        }
+        stack_end(self.stack.as_ref().unwrap())
+    }
+
+    fn stack_peak_usage(&self) -> Option<usize> {
+        self.stack.as_ref().unwrap().peak_usage()
+    }
+}
+
+impl<F: FnOnce(&mut Yieldable) + 'static> HasCoContext for CoState<F> {
+    type F = F;
+
+    fn context(&mut self) -> &mut CoContext<F> {
+        &mut self.ctx
+    }
+
+    fn call(&mut self, f: F) {
+        f(self);
     }
 }
 
 impl<F: FnOnce(&mut Yieldable) + 'static> CommonCoState for CoState<F> {
     fn resume(&mut self) -> Option<&Promise> {
         unsafe {
-            let new_rsp = self.rsp;
-
-            match self.running_state {
-                RunningState::NotStarted => {
-                    self.running_state = RunningState::Running;
-                    let rsp = &mut self.rsp as *mut usize;
-                    let self_raw = self as *mut Self as *mut raw::c_void;
-                    __ll_init_co_stack(rsp, new_rsp, Self::co_initializer, self_raw);
-
-                    if let Some(e) = self.error_val.take() {
-                        resume_unwind(e);
-                    }
-
-                    self.yield_val.val.take().map(|v| &*v)
-                },
-                RunningState::Running => {
-                    __ll_co_yield_now(&mut self.rsp, new_rsp);
-
-                    if let Some(e) = self.error_val.take() {
-                        resume_unwind(e);
-                    }
-
-                    self.yield_val.val.take().map(|v| &*v)
-                },
-                RunningState::Terminated => None
-            }
+            let initial_rsp = self.ctx.rsp;
+            let self_raw = self as *mut Self;
+            self.ctx.switch_in(self_raw, initial_rsp);
+            self.ctx.take_yield_val().map(|v| &*v)
         }
     }
 
     fn take_stack(&mut self) -> Option<Stack> {
         // We can only safely take the stack of an already terminated coroutine.
-        self.ensure_terminated();
+        self.ctx.ensure_terminated();
         self.stack.take()
     }
+
+    fn is_migratable(&self) -> bool {
+        self.migratable
+    }
+}
+
+impl<F: FnOnce(&mut Yieldable) + Send + 'static> CoState<F> {
+    /// Creates a coroutine that can be moved to a scheduler on another
+    /// thread while it is suspended. See `NotifyHandle::into_migratable`
+    /// for the values it may keep on its stack when it moves.
+    pub fn new_migratable(stack: Stack, f: F) -> CoState<F> {
+        let mut co = CoState::new(stack, f);
+        co.migratable = true;
+        co
+    }
 }
 
 impl<F: FnOnce(&mut Yieldable) + 'static> CoState<F> {
@@ -146,58 +337,132 @@ impl<F: FnOnce(&mut Yieldable) + 'static> CoState<F> {
 
         CoState {
             stack: Some(stack),
-            rsp: rsp,
-            yield_val: MaybeYieldVal { val: None },
-            error_val: None,
-            running_state: RunningState::NotStarted,
-            f: Some(f)
+            ctx: CoContext::new(rsp, f),
+            migratable: false
         }
     }
+}
 
-    extern "C" fn co_initializer(user_data: *mut raw::c_void) {
-        let this: &mut Self = unsafe { &mut *(user_data as *mut Self) };
-        {
-            let f = this.f.take().unwrap();
-            if let Err(e) = catch_unwind(AssertUnwindSafe(|| f(this))) {
-                this.error_val = Some(e);
-            }
-        }
+impl<F: FnOnce(&mut Yieldable) + 'static> Drop for CoState<F> {
+    fn drop(&mut self) {
+        self.ctx.ensure_terminated();
+    }
+}
+
+/// A coroutine that receives a value of type `I` every time it is resumed.
+///
+/// The first value is passed to the coroutine function as an argument.
+/// Later values are returned by `DuplexYielder::yield_now`. Both are `None`
+/// if the coroutine is resumed through `CommonCoState::resume`, e.g. by a
+/// scheduler.
+///
+/// Must not be accessed by the coroutine itself.
+pub struct DuplexCoState<I: 'static> {
+    stack: Option<Stack>,
+    ctx: CoContext<DuplexFn<I>>
+}
+
+type DuplexFn<I> = Box<FnOnce(&mut DuplexYielder<I>, Option<I>)>;
+
+/// A duplex coroutine's view of itself.
+///
+/// Only accessible from inside a coroutine.
+pub struct DuplexYielder<'a, I: 'static> {
+    co: &'a mut DuplexCoState<I>
+}
+
+impl<'a, I: 'static> DuplexYielder<'a, I> {
+    /// Suspends the coroutine and returns the value passed to the next
+    /// `DuplexCoState::resume_with`.
+    pub fn yield_now(&mut self, val: &Promise) -> Option<I> {
+        self.co.ctx.yield_now(val);
+        unsafe { self.co.ctx.take_input() }
+    }
+}
+
+impl<'a, I: 'static> Yieldable for DuplexYielder<'a, I> {
+    fn yield_now(&mut self, val: &Promise) {
+        self.co.ctx.yield_now(val);
+    }
+
+    fn stack_begin(&self) -> *mut u8 {
+        stack_begin(self.co.stack.as_ref().unwrap())
+    }
 
-        // No droppable objects should remain at this point.
-        // Otherwise there will be a resource leak.
+    fn stack_end(&self) -> *mut u8 {
+        stack_end(self.co.stack.as_ref().unwrap())
+    }
+
+    fn stack_peak_usage(&self) -> Option<usize> {
+        self.co.stack.as_ref().unwrap().peak_usage()
+    }
+}
+
+impl<I: 'static> HasCoContext for DuplexCoState<I> {
+    type F = DuplexFn<I>;
+
+    fn context(&mut self) -> &mut CoContext<Self::F> {
+        &mut self.ctx
+    }
+
+    fn call(&mut self, f: Self::F) {
+        let first = unsafe { self.ctx.take_input() };
+        f(&mut DuplexYielder { co: self }, first);
+    }
+}
+
+impl<I: 'static> CommonCoState for DuplexCoState<I> {
+    fn resume(&mut self) -> Option<&Promise> {
         unsafe {
-            this.terminate_from_inside();
+            let initial_rsp = self.ctx.rsp;
+            let self_raw = self as *mut Self;
+            self.ctx.switch_in(self_raw, initial_rsp);
+            self.ctx.take_yield_val().map(|v| &*v)
         }
     }
 
-    unsafe fn terminate_from_inside(&mut self) -> ! {
-        self.running_state = RunningState::Terminated;
-
-        self.yield_val.val = None;
+    fn take_stack(&mut self) -> Option<Stack> {
+        // We can only safely take the stack of an already terminated coroutine.
+        self.ctx.ensure_terminated();
+        self.stack.take()
+    }
+}
 
-        let new_rsp = self.rsp;
-        __ll_co_yield_now(&mut self.rsp, new_rsp);
+impl<I: 'static> DuplexCoState<I> {
+    pub fn new<F: FnOnce(&mut DuplexYielder<I>, Option<I>) + 'static>(stack: Stack, f: F) -> DuplexCoState<I> {
+        let rsp: usize = stack.initial_rsp();
 
-        eprintln!("Coroutine termination failed");
-        ::std::process::abort();
+        DuplexCoState {
+            stack: Some(stack),
+            ctx: CoContext::new(rsp, Box::new(f))
+        }
     }
 
-    fn ensure_terminated(&self) {
-        if self.running_state != RunningState::Terminated {
-            panic!("The current coroutine is required to be terminated at this point");
+    /// Resumes the coroutine with `input`.
+    ///
+    /// `input` is dropped if the coroutine has terminated, or terminates
+    /// without consuming it.
+    pub fn resume_with(&mut self, input: I) -> Option<&Promise> {
+        unsafe {
+            let initial_rsp = self.ctx.rsp;
+            let self_raw = self as *mut Self;
+            self.ctx.switch_in_with(self_raw, initial_rsp, input);
+            self.ctx.take_yield_val().map(|v| &*v)
         }
     }
 }
 
-impl<F: FnOnce(&mut Yieldable) + 'static> Drop for CoState<F> {
+impl<I: 'static> Drop for DuplexCoState<I> {
     fn drop(&mut self) {
-        self.ensure_terminated();
+        self.ctx.ensure_terminated();
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::rc::Rc;
+    use std::cell::Cell;
     #[test]
     fn yield_should_work() {
         let mut co = CoState::new(Stack::new(4096), |c| {
@@ -225,8 +490,10 @@ mod tests {
 
     #[test]
     fn panics_should_propagate() {
-        // Use a larger stack size here to make backtrace work
-        let mut co = CoState::new(Stack::new(16384), |_| {
+        // Use a larger stack size here to make backtrace work. The default
+        // panic hook of current toolchains needs more than 16K and overflows
+        // a 16K stack.
+        let mut co = CoState::new(Stack::new(65536), |_| {
             panic!("Test panic");
         });
         let e = catch_unwind(AssertUnwindSafe(|| {
@@ -274,37 +541,166 @@ mod tests {
         assert!(co.take_stack().is_none());
     }
 
-    // The correct behavior for these two tests is to segfault with
-    // a bad permissions error.
-    /*#[test]
-    fn stack_overflow() {
-        fn inner(i: i32) -> i32 {
-            if i == 42 {
-                inner(42)
-            } else {
-                0
+    #[test]
+    fn duplex_resume_values_should_be_received() {
+        let sum: Rc<Cell<i32>> = Rc::new(Cell::new(0));
+        let sum2 = sum.clone();
+
+        let mut co = DuplexCoState::new(Stack::new(4096), move |c, first: Option<i32>| {
+            let mut v = first.unwrap();
+            while v != 0 {
+                sum2.set(sum2.get() + v);
+                v = c.yield_now(&Promise::new_started()).unwrap_or(0);
             }
+        });
+
+        assert!(co.resume_with(1).is_some());
+        assert!(co.resume_with(2).is_some());
+        assert!(co.resume_with(3).is_some());
+        assert_eq!(sum.get(), 6);
+        assert!(co.resume_with(0).is_none());
+        assert!(co.resume_with(42).is_none());
+        assert_eq!(sum.get(), 6);
+        assert!(co.take_stack().is_some());
+    }
+
+    #[test]
+    fn stack_peak_usage_should_be_measured() {
+        #[inline(never)]
+        fn use_stack() {
+            let arr: [u8; 8192] = ::std::hint::black_box([1; 8192]);
+            ::std::hint::black_box(&arr);
+        }
+
+        let mut co = CoState::new(Stack::new_measured(65536), |c| {
+            let before = c.stack_peak_usage().unwrap();
+            assert!(before > 0);
+
+            use_stack();
+            assert!(c.stack_peak_usage().unwrap() >= before + 4096);
+        });
+        assert!(co.resume().is_none());
+        assert!(co.take_stack().unwrap().peak_usage().unwrap() >= 8192);
+
+        let mut co = CoState::new(Stack::new(16384), |c| {
+            assert!(c.stack_peak_usage().is_none());
+        });
+        assert!(co.resume().is_none());
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn get_fp_control() -> (u32, u16) {
+        use std::arch::asm;
+
+        let mut mxcsr: u32 = 0;
+        let mut cw: u16 = 0;
+        unsafe {
+            asm!("stmxcsr [{}]", in(reg) &mut mxcsr);
+            asm!("fnstcw [{}]", in(reg) &mut cw);
+        }
+        (mxcsr, cw)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn set_fp_control(mxcsr: u32, cw: u16) {
+        use std::arch::asm;
+
+        unsafe {
+            asm!("ldmxcsr [{}]", in(reg) &mxcsr);
+            asm!("fldcw [{}]", in(reg) &cw);
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn fp_control_state_should_not_leak() {
+        // Rounding control bits.
+        const MXCSR_RC_MASK: u32 = 0x6000;
+        const CW_RC_MASK: u16 = 0x0c00;
+
+        let (init_mxcsr, init_cw) = get_fp_control();
+
+        let mut co1 = CoState::new(Stack::new(4096), move |c| {
+            // Round toward zero.
+            set_fp_control(init_mxcsr | MXCSR_RC_MASK, init_cw | CW_RC_MASK);
+            c.yield_now(&Promise::new_started());
+            assert_eq!(get_fp_control(), (init_mxcsr | MXCSR_RC_MASK, init_cw | CW_RC_MASK));
+        });
+        let mut co2 = CoState::new(Stack::new(4096), move |c| {
+            assert_eq!(get_fp_control(), (init_mxcsr, init_cw));
+            c.yield_now(&Promise::new_started());
+            assert_eq!(get_fp_control(), (init_mxcsr, init_cw));
+        });
+
+        assert!(co1.resume().is_some());
+        assert_eq!(get_fp_control(), (init_mxcsr, init_cw));
+        assert!(co2.resume().is_some());
+        assert!(co1.resume().is_none());
+        assert_eq!(get_fp_control(), (init_mxcsr, init_cw));
+        assert!(co2.resume().is_none());
+        assert_eq!(get_fp_control(), (init_mxcsr, init_cw));
+    }
+
+    /// Runs the test named `name` in a child process with overflow detection
+    /// enabled, and checks that it aborts with a stack overflow report.
+    fn expect_stack_overflow(name: &str, f: fn()) {
+        use std::env;
+        use std::process::Command;
+
+        if env::var("LL_OVERFLOW_DEATH_TEST").is_ok() {
+            ::overflow::install_handler();
+            f();
+            unreachable!();
         }
 
-        let mut co = CoState::new(4096, |_| {
-            inner(42);
+        let output = Command::new(env::current_exe().unwrap())
+            .arg("--exact")
+            .arg(name)
+            .arg("--nocapture")
+            .env("LL_OVERFLOW_DEATH_TEST", "1")
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("coroutine stack overflow"), "Unexpected output: {}", stderr);
+        assert!(stderr.contains(&format!("({} bytes)", 4096)), "Unexpected output: {}", stderr);
+    }
+
+    #[test]
+    fn stack_overflow() {
+        expect_stack_overflow("co::tests::stack_overflow", || {
+            fn inner(i: i32) -> i32 {
+                let arr: [i32; 16] = [i; 16];
+                if ::std::hint::black_box(i) == 42 {
+                    inner(42) + arr[15]
+                } else {
+                    0
+                }
+            }
+
+            let mut co = CoState::new(Stack::new(4096), |_| {
+                inner(42);
+            });
+            co.resume();
         });
-        co.resume();
     }
 
     #[test]
     fn really_big_stack_overflow() {
-        fn inner(v: i32) -> i32 {
-            let mut arr: [i32; 8192] = [0; 8192];
-            arr[0] = v;
-            for i in 1..8192 {
-                arr[i] = arr[i - 1] + arr[8192 - i - 1] + 1;
+        expect_stack_overflow("co::tests::really_big_stack_overflow", || {
+            fn inner(v: i32) -> i32 {
+                let mut arr: [i32; 8192] = [0; 8192];
+                arr[0] = v;
+                for i in 1..8192 {
+                    arr[i] = arr[i - 1] + arr[8192 - i - 1] + 1;
+                }
+                ::std::hint::black_box(arr)[1000]
             }
-            arr[1000]
-        }
-        let mut co = CoState::new(4096, |_| {
-            inner(42);
+            let mut co = CoState::new(Stack::new(4096), |_| {
+                inner(42);
+            });
+            co.resume();
         });
-        co.resume();
-    }*/
+    }
 }
@@ -0,0 +1,76 @@
+use std::fmt;
+use std::io;
+use std::error;
+
+/// The operation that failed.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ErrorKind {
+    /// Mapping stack memory (`mmap`).
+    StackAllocation,
+
+    /// Protecting the guard page of a stack (`mprotect`).
+    GuardPage,
+
+    /// Unmapping stack memory (`munmap`).
+    StackRelease,
+
+    /// Giving the pages of an idle stack back to the OS (`madvise`).
+    PageDiscard,
+
+    /// Querying which pages of a stack are resident (`mincore`).
+    ResidencyQuery,
+
+    /// A requested stack size of zero, or too big to be represented.
+    InvalidStackSize
+}
+
+/// An error from the OS, carrying its errno.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Error {
+    kind: ErrorKind,
+    errno: i32
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind, errno: i32) -> Error {
+        Error {
+            kind: kind,
+            errno: errno
+        }
+    }
+
+    /// Creates an error from the errno of the last failed system call.
+    pub(crate) fn last_os_error(kind: ErrorKind) -> Error {
+        Error::new(kind, io::Error::last_os_error().raw_os_error().unwrap_or(0))
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    pub fn errno(&self) -> i32 {
+        self.errno
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let what = match self.kind {
+            ErrorKind::StackAllocation => "stack allocation failed",
+            ErrorKind::GuardPage => "setting up stack guard page failed",
+            ErrorKind::StackRelease => "stack release failed",
+            ErrorKind::PageDiscard => "discarding stack pages failed",
+            ErrorKind::ResidencyQuery => "querying resident stack pages failed",
+            ErrorKind::InvalidStackSize => "invalid stack size"
+        };
+        write!(f, "{}: {}", what, io::Error::from_raw_os_error(self.errno))
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        io::Error::from_raw_os_error(e.errno)
+    }
+}
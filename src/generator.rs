@@ -0,0 +1,228 @@
+use std::rc::Rc;
+use std::cell::Cell;
+use std::thread;
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+use co::{CommonCoState, CoState, Yieldable};
+use stack::Stack;
+use promise::Promise;
+
+/// The result of resuming a generator.
+#[derive(Debug, Eq, PartialEq)]
+pub enum GeneratorState<Y, R> {
+    Yielded(Y),
+    Complete(R)
+}
+
+/// Unwinding payload used to tear down a generator that is dropped
+/// before completion.
+struct GeneratorCancelled;
+
+struct GeneratorSlot<Y, R> {
+    yielded: Cell<Option<Y>>,
+    returned: Cell<Option<R>>,
+    cancelled: Cell<bool>
+}
+
+/// A generator's view of itself.
+///
+/// Only accessible from inside a generator.
+pub struct GeneratorContext<'a, Y: 'a> {
+    co: &'a mut Yieldable,
+    yielded: &'a Cell<Option<Y>>,
+    cancelled: &'a Cell<bool>
+}
+
+impl<'a, Y: 'a> GeneratorContext<'a, Y> {
+    /// Hands `val` to the caller of `Generator::resume` and suspends
+    /// the generator until it is resumed again.
+    pub fn yield_value(&mut self, val: Y) {
+        self.yielded.set(Some(val));
+        self.co.yield_now(&Promise::new_started());
+
+        // The generator is being dropped. Unwind its stack so that
+        // everything owned by it gets released.
+        if self.cancelled.get() {
+            resume_unwind(Box::new(GeneratorCancelled));
+        }
+    }
+}
+
+/// A coroutine that yields values of type `Y` and completes with
+/// a value of type `R`.
+///
+/// Dropping an unfinished generator unwinds its stack.
+pub struct Generator<Y: 'static, R: 'static = ()> {
+    co: Box<CommonCoState>,
+    slot: Rc<GeneratorSlot<Y, R>>,
+    finished: bool
+}
+
+impl<Y: 'static, R: 'static> Generator<Y, R> {
+    pub fn new<F: FnOnce(&mut GeneratorContext<Y>) -> R + 'static>(stack: Stack, f: F) -> Generator<Y, R> {
+        let slot = Rc::new(GeneratorSlot {
+            yielded: Cell::new(None),
+            returned: Cell::new(None),
+            cancelled: Cell::new(false)
+        });
+        let slot2 = slot.clone();
+
+        let co = CoState::new(stack, move |c| {
+            if slot2.cancelled.get() {
+                return;
+            }
+
+            let ret = f(&mut GeneratorContext {
+                co: c,
+                yielded: &slot2.yielded,
+                cancelled: &slot2.cancelled
+            });
+            slot2.returned.set(Some(ret));
+        });
+
+        Generator {
+            co: Box::new(co),
+            slot: slot,
+            finished: false
+        }
+    }
+
+    /// Runs the generator until it yields or completes.
+    ///
+    /// Panics inside the generator are propagated to the caller.
+    pub fn resume(&mut self) -> GeneratorState<Y, R> {
+        if self.finished {
+            panic!("Attempting to resume a completed generator");
+        }
+
+        if self.co.resume().is_some() {
+            GeneratorState::Yielded(self.slot.yielded.take().unwrap())
+        } else {
+            self.finished = true;
+            match self.slot.returned.take() {
+                Some(v) => GeneratorState::Complete(v),
+                None => panic!("Attempting to resume a panicked generator")
+            }
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Takes the stack of a completed generator, e.g. to put it back into
+    /// a `StackPool`.
+    pub fn take_stack(&mut self) -> Option<Stack> {
+        self.co.take_stack()
+    }
+}
+
+impl<Y: 'static> Iterator for Generator<Y, ()> {
+    type Item = Y;
+
+    fn next(&mut self) -> Option<Y> {
+        if self.finished {
+            return None;
+        }
+
+        match self.resume() {
+            GeneratorState::Yielded(v) => Some(v),
+            GeneratorState::Complete(()) => None
+        }
+    }
+}
+
+impl<Y: 'static, R: 'static> Drop for Generator<Y, R> {
+    fn drop(&mut self) {
+        self.slot.cancelled.set(true);
+
+        // Resuming a terminated coroutine is a no-op, so this also works
+        // for generators that have completed or panicked.
+        loop {
+            match catch_unwind(AssertUnwindSafe(|| self.co.resume().is_some())) {
+                Ok(true) => {},
+                Ok(false) => break,
+                Err(e) => {
+                    if !e.is::<GeneratorCancelled>() && !thread::panicking() {
+                        resume_unwind(e);
+                    }
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_should_be_yielded() {
+        let mut gen = Generator::new(Stack::new(16384), |c| {
+            c.yield_value(1);
+            c.yield_value(2);
+            "done"
+        });
+
+        assert_eq!(gen.resume(), GeneratorState::Yielded(1));
+        assert_eq!(gen.resume(), GeneratorState::Yielded(2));
+        assert_eq!(gen.resume(), GeneratorState::Complete("done"));
+        assert!(gen.is_finished());
+        assert!(gen.take_stack().is_some());
+    }
+
+    #[test]
+    fn iterator_should_work() {
+        let gen = Generator::new(Stack::new(16384), |c| {
+            for i in 0..5 {
+                c.yield_value(i * 2);
+            }
+        });
+        let v: Vec<i32> = gen.collect();
+        assert_eq!(v, vec![0, 2, 4, 6, 8]);
+    }
+
+    #[test]
+    fn panics_should_propagate() {
+        // Use a larger stack size here to make backtrace work
+        let mut gen: Generator<i32, ()> = Generator::new(Stack::new(65536), |_| {
+            panic!("Test panic");
+        });
+        let e = catch_unwind(AssertUnwindSafe(|| {
+            gen.resume();
+        })).err().unwrap();
+        let v: &&'static str = e.downcast_ref().unwrap();
+        assert_eq!(*v, "Test panic");
+    }
+
+    #[test]
+    fn dropping_unfinished_generator_should_release_resources() {
+        let value: Rc<()> = Rc::new(());
+        let value2 = value.clone();
+
+        let mut gen = Generator::new(Stack::new(16384), move |c| {
+            let _v = value2;
+            loop {
+                c.yield_value(());
+            }
+        });
+        assert_eq!(gen.next(), Some(()));
+        assert_eq!(Rc::strong_count(&value), 2);
+
+        drop(gen);
+        assert_eq!(Rc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn dropping_unstarted_generator_should_work() {
+        let value: Rc<()> = Rc::new(());
+        let value2 = value.clone();
+
+        let gen: Generator<(), ()> = Generator::new(Stack::new(16384), move |_| {
+            let _v = value2;
+            panic!("Should not run");
+        });
+        drop(gen);
+        assert_eq!(Rc::strong_count(&value), 1);
+    }
+}
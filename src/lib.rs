@@ -5,13 +5,29 @@ extern crate lazy_static;
 pub mod co;
 pub mod stack;
 pub mod stack_pool;
+pub mod sync_stack_pool;
 pub mod scheduler;
 pub mod promise;
+pub mod generator;
+pub mod overflow;
+pub mod shared_stack;
+pub mod error;
+pub mod timer;
+pub mod reactor;
+pub mod net;
+pub mod thread_pool;
+pub mod sync;
 mod invoke_box;
+mod mpsc_queue;
 mod platform;
 
-pub use co::{CoState, Yieldable};
+pub use co::{CoState, DuplexCoState, Yieldable};
 pub use stack::Stack;
-pub use stack_pool::{StackPool, StackPoolConfig};
+pub use shared_stack::{SharedStack, SharedStackCoState};
+pub use stack_pool::{StackPool, StackPoolConfig, StackPoolStats, StackSizeClass, StackTrimPolicy};
+pub use sync_stack_pool::{SyncStackPool, SyncStackPoolConfig};
 pub use promise::Promise;
 pub use scheduler::{Scheduler, SchedulerConfig};
+pub use thread_pool::{ThreadPoolScheduler, ThreadPoolConfig, ThreadPoolHandle, SpawnError};
+pub use error::{Error, ErrorKind};
+pub use generator::{Generator, GeneratorState};
@@ -0,0 +1,242 @@
+//! An intrusive lock-free multi-producer single-consumer queue of coroutines.
+//!
+//! Based on Dmitry Vyukov's intrusive MPSC node-based queue. The links are
+//! allocated together with the coroutine states, so that pushing never
+//! allocates.
+
+use std::cell::{Cell, UnsafeCell};
+use std::ops::{Deref, DerefMut};
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use co::CommonCoState;
+
+struct QueueLink {
+    next: AtomicPtr<QueueLink>,
+
+    // Points back to the coroutine owning this link while queued.
+    owner: Cell<Option<*mut Linked<CommonCoState>>>
+}
+
+impl QueueLink {
+    fn new() -> QueueLink {
+        QueueLink {
+            next: AtomicPtr::new(null_mut()),
+            owner: Cell::new(None)
+        }
+    }
+}
+
+/// A coroutine state preceded by the link that queues it.
+pub(crate) struct Linked<T: ?Sized> {
+    link: QueueLink,
+    co: T
+}
+
+impl<T> Linked<T> {
+    pub(crate) fn new(co: T) -> Linked<T> {
+        Linked {
+            link: QueueLink::new(),
+            co: co
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for Linked<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.co
+    }
+}
+
+impl<T: ?Sized> DerefMut for Linked<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.co
+    }
+}
+
+/// A coroutine as owned by schedulers.
+pub(crate) type CoBox = Box<Linked<CommonCoState>>;
+
+pub(crate) struct CoQueue {
+    // The most recently pushed link. Written by producers.
+    head: AtomicPtr<QueueLink>,
+
+    // The next link to pop. Only touched by the consumer.
+    tail: UnsafeCell<*mut QueueLink>,
+
+    // Sits in the queue when it would otherwise be empty.
+    stub: Box<QueueLink>
+}
+
+unsafe impl Send for CoQueue {}
+unsafe impl Sync for CoQueue {}
+
+impl CoQueue {
+    pub(crate) fn new() -> CoQueue {
+        let stub = Box::new(QueueLink::new());
+        let stub_ptr = &*stub as *const QueueLink as *mut QueueLink;
+
+        CoQueue {
+            head: AtomicPtr::new(stub_ptr),
+            tail: UnsafeCell::new(stub_ptr),
+            stub: stub
+        }
+    }
+
+    fn stub_ptr(&self) -> *mut QueueLink {
+        &*self.stub as *const QueueLink as *mut QueueLink
+    }
+
+    /// Pushes `co`. Safe to call from any thread.
+    pub(crate) fn push(&self, co: CoBox) {
+        let co = Box::into_raw(co);
+        unsafe {
+            let link = &(*co).link;
+            link.owner.set(Some(co));
+            self.push_link(link as *const QueueLink as *mut QueueLink);
+        }
+    }
+
+    unsafe fn push_link(&self, link: *mut QueueLink) {
+        (*link).next.store(null_mut(), Ordering::Relaxed);
+        let prev = self.head.swap(link, Ordering::SeqCst);
+
+        // The queue is disconnected between the swap and this store. The
+        // consumer treats that window as empty.
+        (*prev).next.store(link, Ordering::Release);
+    }
+
+    /// Returns whether the queue is empty, including pushes in progress.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.head.load(Ordering::SeqCst) == self.stub_ptr()
+    }
+
+    /// Pops the oldest coroutine.
+    ///
+    /// Returns `None` if the queue is empty, or if a producer is in the
+    /// middle of a push. In the latter case, `is_empty` returns false.
+    ///
+    /// Must only be called from one thread at a time.
+    pub(crate) unsafe fn pop(&self) -> Option<CoBox> {
+        let stub = self.stub_ptr();
+        let tail_slot = &mut *self.tail.get();
+
+        let mut tail = *tail_slot;
+        let mut next = (*tail).next.load(Ordering::Acquire);
+
+        if tail == stub {
+            if next.is_null() {
+                return None;
+            }
+            *tail_slot = next;
+            tail = next;
+            next = (*next).next.load(Ordering::Acquire);
+        }
+
+        if next.is_null() {
+            if tail != self.head.load(Ordering::Acquire) {
+                // A push is in progress.
+                return None;
+            }
+
+            // `tail` is the last link. Put the stub behind it so that it
+            // can be taken out.
+            self.push_link(stub);
+            next = (*tail).next.load(Ordering::Acquire);
+            if next.is_null() {
+                return None;
+            }
+        }
+
+        *tail_slot = next;
+        let co = (*tail).owner.replace(None).unwrap();
+        Some(Box::from_raw(co))
+    }
+}
+
+impl Drop for CoQueue {
+    fn drop(&mut self) {
+        unsafe {
+            while let Some(co) = self.pop() {
+                drop(co);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use std::thread;
+    use co::CoState;
+    use stack::Stack;
+
+    fn finish(co: &mut CommonCoState) {
+        assert!(co.resume().is_none());
+    }
+
+    #[test]
+    fn coroutines_should_be_popped_in_order() {
+        let queue = CoQueue::new();
+        let order: Rc<Cell<usize>> = Rc::new(Cell::new(0));
+
+        unsafe {
+            assert!(queue.is_empty());
+            assert!(queue.pop().is_none());
+
+            for round in 0..3 {
+                for i in 0..4 {
+                    let order = order.clone();
+                    queue.push(Box::new(Linked::new(CoState::new(Stack::new(16384), move |_| {
+                        assert_eq!(order.get(), i);
+                        order.set(i + 1);
+                    }))));
+                }
+                assert!(!queue.is_empty());
+
+                order.set(0);
+                for _ in 0..4 {
+                    finish(&mut **queue.pop().unwrap());
+                }
+                assert_eq!(order.get(), 4, "round {}", round);
+                assert!(queue.is_empty());
+                assert!(queue.pop().is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn many_producers_should_push_concurrently() {
+        const PRODUCERS: usize = 4;
+        const PER_PRODUCER: usize = 256;
+
+        let queue = Arc::new(CoQueue::new());
+        let producers: Vec<thread::JoinHandle<()>> = (0..PRODUCERS).map(|_| {
+            let queue = queue.clone();
+            thread::spawn(move || {
+                for _ in 0..PER_PRODUCER {
+                    queue.push(Box::new(Linked::new(CoState::new(Stack::new(16384), |_| {}))));
+                }
+            })
+        }).collect();
+
+        let mut popped: usize = 0;
+        while popped < PRODUCERS * PER_PRODUCER {
+            match unsafe { queue.pop() } {
+                Some(mut co) => {
+                    finish(&mut **co);
+                    popped += 1;
+                },
+                None => thread::yield_now()
+            }
+        }
+
+        for t in producers {
+            t.join().unwrap();
+        }
+        assert!(queue.is_empty());
+    }
+}
@@ -0,0 +1,183 @@
+//! Coroutine-aware sockets.
+//!
+//! Sockets are non-blocking. Operations take the current `Yieldable` and
+//! suspend the coroutine until the socket is ready, instead of blocking the
+//! thread. Must only be used from coroutines running on a `Scheduler`.
+
+use std::io;
+use std::mem;
+use std::net::{ToSocketAddrs, SocketAddr, SocketAddrV4, SocketAddrV6, Ipv4Addr, Ipv6Addr};
+use std::os::unix::io::RawFd;
+use libc;
+use co::Yieldable;
+use reactor::{self, Interest};
+
+pub mod tcp;
+pub mod udp;
+pub mod unix;
+
+pub use self::tcp::{TcpListener, TcpStream, TcpStreamIo};
+pub use self::udp::UdpSocket;
+pub use self::unix::{UnixListener, UnixStream, UnixStreamIo, UnixDatagram, UCred};
+
+/// Runs `f` until it stops failing with `WouldBlock`, suspending the
+/// current coroutine on `fd` in between.
+pub(crate) fn retry<T, F: FnMut() -> io::Result<T>>(c: &mut Yieldable, fd: RawFd, interest: Interest, mut f: F) -> io::Result<T> {
+    loop {
+        match f() {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            r => return r
+        }
+        c.yield_now(&reactor::readiness_promise(fd, interest));
+    }
+}
+
+/// Resolves `addr` and runs `f` on each address until one succeeds.
+///
+/// Name resolution blocks the thread.
+pub(crate) fn each_addr<A: ToSocketAddrs, T, F: FnMut(&SocketAddr) -> io::Result<T>>(addr: A, mut f: F) -> io::Result<T> {
+    let mut last_err = None;
+    for addr in addr.to_socket_addrs()? {
+        match f(&addr) {
+            Ok(v) => return Ok(v),
+            Err(e) => last_err = Some(e)
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses")
+    }))
+}
+
+/// Converts the return value of a libc call into an `io::Result`.
+pub(crate) fn cvt(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+pub(crate) fn cvt_size(ret: libc::ssize_t) -> io::Result<usize> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+/// Creates a non-blocking, close-on-exec socket.
+pub(crate) fn new_socket(family: libc::c_int, ty: libc::c_int) -> io::Result<RawFd> {
+    unsafe {
+        cvt(libc::socket(family, ty | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC, 0))
+    }
+}
+
+pub(crate) fn socket_family(addr: &SocketAddr) -> libc::c_int {
+    match *addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6
+    }
+}
+
+pub(crate) fn socket_addr_to_raw(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    unsafe {
+        let mut storage: libc::sockaddr_storage = mem::zeroed();
+        let len = match *addr {
+            SocketAddr::V4(ref a) => {
+                let raw = &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in);
+                raw.sin_family = libc::AF_INET as libc::sa_family_t;
+                raw.sin_port = a.port().to_be();
+                raw.sin_addr = libc::in_addr {
+                    s_addr: u32::from(*a.ip()).to_be()
+                };
+                mem::size_of::<libc::sockaddr_in>()
+            },
+            SocketAddr::V6(ref a) => {
+                let raw = &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6);
+                raw.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                raw.sin6_port = a.port().to_be();
+                raw.sin6_flowinfo = a.flowinfo();
+                raw.sin6_addr.s6_addr = a.ip().octets();
+                raw.sin6_scope_id = a.scope_id();
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+        (storage, len as libc::socklen_t)
+    }
+}
+
+pub(crate) fn raw_to_socket_addr(storage: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+    unsafe {
+        match storage.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let raw = &*(storage as *const _ as *const libc::sockaddr_in);
+                Ok(SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::from(u32::from_be(raw.sin_addr.s_addr)),
+                    u16::from_be(raw.sin_port)
+                )))
+            },
+            libc::AF_INET6 => {
+                let raw = &*(storage as *const _ as *const libc::sockaddr_in6);
+                Ok(SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(raw.sin6_addr.s6_addr),
+                    u16::from_be(raw.sin6_port),
+                    raw.sin6_flowinfo,
+                    raw.sin6_scope_id
+                )))
+            },
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "unsupported address family"))
+        }
+    }
+}
+
+pub(crate) fn setsockopt<T: Copy>(fd: RawFd, level: libc::c_int, name: libc::c_int, value: T) -> io::Result<()> {
+    unsafe {
+        cvt(libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t
+        )).map(|_| ())
+    }
+}
+
+pub(crate) fn getsockopt<T: Copy>(fd: RawFd, level: libc::c_int, name: libc::c_int) -> io::Result<T> {
+    unsafe {
+        let mut value: T = mem::zeroed();
+        let mut len = mem::size_of::<T>() as libc::socklen_t;
+        cvt(libc::getsockopt(
+            fd,
+            level,
+            name,
+            &mut value as *mut T as *mut libc::c_void,
+            &mut len
+        ))?;
+        Ok(value)
+    }
+}
+
+/// Creates a socket of type `ty` bound to `addr`.
+pub(crate) fn bind_socket(addr: &SocketAddr, ty: libc::c_int) -> io::Result<RawFd> {
+    let fd = new_socket(socket_family(addr), ty)?;
+    let (raw, len) = socket_addr_to_raw(addr);
+    unsafe {
+        if let Err(e) = cvt(libc::bind(fd, &raw as *const _ as *const libc::sockaddr, len)) {
+            libc::close(fd);
+            return Err(e);
+        }
+    }
+    Ok(fd)
+}
+
+/// Takes the pending error of a socket, e.g. the result of a non-blocking
+/// `connect`.
+pub(crate) fn take_socket_error(fd: RawFd) -> io::Result<Option<io::Error>> {
+    let err: libc::c_int = getsockopt(fd, libc::SOL_SOCKET, libc::SO_ERROR)?;
+    if err == 0 {
+        Ok(None)
+    } else {
+        Ok(Some(io::Error::from_raw_os_error(err)))
+    }
+}
@@ -0,0 +1,422 @@
+use std::io::{self, Read, Write, IoSlice, IoSliceMut};
+use std::mem;
+use std::net::{self, SocketAddr, ToSocketAddrs, Shutdown};
+use std::os::unix::io::{RawFd, AsRawFd, FromRawFd, IntoRawFd};
+use std::time::Duration;
+use libc;
+use co::Yieldable;
+use reactor::{self, Interest};
+use super::{each_addr, retry, cvt, new_socket, socket_family, socket_addr_to_raw, raw_to_socket_addr, setsockopt, getsockopt, take_socket_error};
+
+const LISTEN_BACKLOG: libc::c_int = 1024;
+
+/// A TCP socket server.
+pub struct TcpListener {
+    inner: net::TcpListener
+}
+
+impl TcpListener {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<TcpListener> {
+        each_addr(addr, |addr| {
+            let fd = new_socket(socket_family(addr), libc::SOCK_STREAM)?;
+            let inner = unsafe { net::TcpListener::from_raw_fd(fd) };
+
+            setsockopt::<libc::c_int>(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
+            let (raw, len) = socket_addr_to_raw(addr);
+            unsafe {
+                cvt(libc::bind(fd, &raw as *const _ as *const libc::sockaddr, len))?;
+                cvt(libc::listen(fd, LISTEN_BACKLOG))?;
+            }
+
+            Ok(TcpListener {
+                inner: inner
+            })
+        })
+    }
+
+    /// Takes over a listener from the standard library, switching it
+    /// to non-blocking mode.
+    pub fn from_std(inner: net::TcpListener) -> io::Result<TcpListener> {
+        inner.set_nonblocking(true)?;
+        Ok(TcpListener {
+            inner: inner
+        })
+    }
+
+    /// Accepts a new connection, suspending the current coroutine until
+    /// one arrives.
+    pub fn accept(&self, c: &mut Yieldable) -> io::Result<(TcpStream, SocketAddr)> {
+        let fd = self.inner.as_raw_fd();
+        let (stream_fd, storage) = retry(c, fd, Interest::Readable, || unsafe {
+            let mut storage: libc::sockaddr_storage = mem::zeroed();
+            let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            let stream_fd = cvt(libc::accept4(
+                fd,
+                &mut storage as *mut _ as *mut libc::sockaddr,
+                &mut len,
+                libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC
+            ))?;
+            Ok((stream_fd, storage))
+        })?;
+
+        let stream = TcpStream {
+            inner: unsafe { net::TcpStream::from_raw_fd(stream_fd) }
+        };
+        Ok((stream, raw_to_socket_addr(&storage)?))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        self.inner.set_ttl(ttl)
+    }
+
+    pub fn ttl(&self) -> io::Result<u32> {
+        self.inner.ttl()
+    }
+
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        self.inner.take_error()
+    }
+}
+
+impl AsRawFd for TcpListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for TcpListener {
+    fn into_raw_fd(self) -> RawFd {
+        self.inner.into_raw_fd()
+    }
+}
+
+/// A TCP stream between a local and a remote socket.
+///
+/// All operations take `&self`, so a stream shared between two coroutines
+/// can be read by one and written by the other at the same time.
+pub struct TcpStream {
+    inner: net::TcpStream
+}
+
+impl TcpStream {
+    /// Opens a connection to `addr`, suspending the current coroutine until
+    /// it is established.
+    pub fn connect<A: ToSocketAddrs>(c: &mut Yieldable, addr: A) -> io::Result<TcpStream> {
+        each_addr(addr, |addr| {
+            let fd = new_socket(socket_family(addr), libc::SOCK_STREAM)?;
+            let stream = TcpStream {
+                inner: unsafe { net::TcpStream::from_raw_fd(fd) }
+            };
+
+            let (raw, len) = socket_addr_to_raw(addr);
+            let ret = unsafe {
+                cvt(libc::connect(fd, &raw as *const _ as *const libc::sockaddr, len))
+            };
+            match ret {
+                Ok(_) => return Ok(stream),
+                Err(ref e) if e.raw_os_error() == Some(libc::EINPROGRESS) || e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e)
+            }
+
+            reactor::wait_writable(c, fd);
+            match take_socket_error(fd)? {
+                Some(e) => Err(e),
+                None => Ok(stream)
+            }
+        })
+    }
+
+    /// Takes over a stream from the standard library, switching it
+    /// to non-blocking mode.
+    pub fn from_std(inner: net::TcpStream) -> io::Result<TcpStream> {
+        inner.set_nonblocking(true)?;
+        Ok(TcpStream {
+            inner: inner
+        })
+    }
+
+    /// Returns an adapter implementing `Read` and `Write` for use by the
+    /// coroutine `c`.
+    pub fn io<'a>(&'a self, c: &'a mut Yieldable) -> TcpStreamIo<'a> {
+        TcpStreamIo {
+            stream: self,
+            co: c
+        }
+    }
+
+    pub fn read(&self, c: &mut Yieldable, buf: &mut [u8]) -> io::Result<usize> {
+        retry(c, self.as_raw_fd(), Interest::Readable, || (&self.inner).read(buf))
+    }
+
+    pub fn read_vectored(&self, c: &mut Yieldable, bufs: &mut [IoSliceMut]) -> io::Result<usize> {
+        retry(c, self.as_raw_fd(), Interest::Readable, || (&self.inner).read_vectored(bufs))
+    }
+
+    pub fn write(&self, c: &mut Yieldable, buf: &[u8]) -> io::Result<usize> {
+        retry(c, self.as_raw_fd(), Interest::Writable, || (&self.inner).write(buf))
+    }
+
+    pub fn write_vectored(&self, c: &mut Yieldable, bufs: &[IoSlice]) -> io::Result<usize> {
+        retry(c, self.as_raw_fd(), Interest::Writable, || (&self.inner).write_vectored(bufs))
+    }
+
+    /// Reads without removing the data from the receive queue.
+    pub fn peek(&self, c: &mut Yieldable, buf: &mut [u8]) -> io::Result<usize> {
+        retry(c, self.as_raw_fd(), Interest::Readable, || self.inner.peek(buf))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.inner.set_nodelay(nodelay)
+    }
+
+    pub fn nodelay(&self) -> io::Result<bool> {
+        self.inner.nodelay()
+    }
+
+    /// Enables TCP keepalive with the given idle time before the first probe,
+    /// or disables it with `None`.
+    pub fn set_keepalive(&self, idle: Option<Duration>) -> io::Result<()> {
+        let fd = self.as_raw_fd();
+        if let Some(idle) = idle {
+            let secs = ::std::cmp::max(idle.as_secs(), 1);
+            let secs = ::std::cmp::min(secs, libc::c_int::MAX as u64) as libc::c_int;
+            setsockopt::<libc::c_int>(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, secs)?;
+        }
+        setsockopt::<libc::c_int>(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, idle.is_some() as libc::c_int)
+    }
+
+    pub fn keepalive(&self) -> io::Result<Option<Duration>> {
+        let fd = self.as_raw_fd();
+        let enabled: libc::c_int = getsockopt(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE)?;
+        if enabled == 0 {
+            return Ok(None);
+        }
+        let secs: libc::c_int = getsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE)?;
+        Ok(Some(Duration::from_secs(secs as u64)))
+    }
+
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        self.inner.set_ttl(ttl)
+    }
+
+    pub fn ttl(&self) -> io::Result<u32> {
+        self.inner.ttl()
+    }
+
+    pub fn set_recv_buffer_size(&self, size: usize) -> io::Result<()> {
+        setsockopt::<libc::c_int>(self.as_raw_fd(), libc::SOL_SOCKET, libc::SO_RCVBUF, size as libc::c_int)
+    }
+
+    pub fn recv_buffer_size(&self) -> io::Result<usize> {
+        getsockopt::<libc::c_int>(self.as_raw_fd(), libc::SOL_SOCKET, libc::SO_RCVBUF).map(|v| v as usize)
+    }
+
+    pub fn set_send_buffer_size(&self, size: usize) -> io::Result<()> {
+        setsockopt::<libc::c_int>(self.as_raw_fd(), libc::SOL_SOCKET, libc::SO_SNDBUF, size as libc::c_int)
+    }
+
+    pub fn send_buffer_size(&self) -> io::Result<usize> {
+        getsockopt::<libc::c_int>(self.as_raw_fd(), libc::SOL_SOCKET, libc::SO_SNDBUF).map(|v| v as usize)
+    }
+
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        self.inner.take_error()
+    }
+}
+
+impl AsRawFd for TcpStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for TcpStream {
+    fn into_raw_fd(self) -> RawFd {
+        self.inner.into_raw_fd()
+    }
+}
+
+/// A `TcpStream` bound to the coroutine using it.
+pub struct TcpStreamIo<'a> {
+    stream: &'a TcpStream,
+    co: &'a mut Yieldable
+}
+
+impl<'a> Read for TcpStreamIo<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(self.co, buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut]) -> io::Result<usize> {
+        self.stream.read_vectored(self.co, bufs)
+    }
+}
+
+impl<'a> Write for TcpStreamIo<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(self.co, buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        self.stream.write_vectored(self.co, bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::cell::Cell;
+    use scheduler::Scheduler;
+    use timer;
+
+    #[test]
+    fn echo_server_should_serve_many_connections() {
+        const N: usize = 2000;
+
+        let mut sched = Scheduler::new_default();
+        let state = sched.get_state();
+        let listener = Rc::new(TcpListener::bind("127.0.0.1:0").unwrap());
+        let addr = listener.local_addr().unwrap();
+        let done: Rc<Cell<usize>> = Rc::new(Cell::new(0));
+
+        let state2 = state.clone();
+        state.start_coroutine(move |c| {
+            for _ in 0..N {
+                let (stream, peer) = listener.accept(c).unwrap();
+                assert_eq!(stream.peer_addr().unwrap(), peer);
+                state2.start_coroutine(move |c| {
+                    let mut buf = [0u8; 256];
+                    loop {
+                        let n = stream.read(c, &mut buf).unwrap();
+                        if n == 0 {
+                            break;
+                        }
+                        stream.io(c).write_all(&buf[..n]).unwrap();
+                    }
+                });
+            }
+        });
+
+        for i in 0..N {
+            let done = done.clone();
+            state.start_coroutine(move |c| {
+                let stream = TcpStream::connect(c, addr).unwrap();
+                stream.set_nodelay(true).unwrap();
+
+                let id = i.to_string();
+                let bufs = [IoSlice::new(b"hello "), IoSlice::new(id.as_bytes())];
+                let n = stream.write_vectored(c, &bufs).unwrap();
+                assert_eq!(n, 6 + id.len());
+                stream.shutdown(Shutdown::Write).unwrap();
+
+                let mut echoed = Vec::new();
+                stream.io(c).read_to_end(&mut echoed).unwrap();
+                assert_eq!(echoed, format!("hello {}", id).into_bytes());
+                done.set(done.get() + 1);
+            });
+        }
+
+        let done2 = done.clone();
+        let vp = state.prepare_coroutine(move |c| {
+            while done2.get() < N {
+                timer::sleep(c, Duration::from_millis(1));
+            }
+        });
+        sched.run_value_promise_to_end(vp).unwrap();
+        assert_eq!(done.get(), N);
+    }
+
+    #[test]
+    fn vectored_reads_should_fill_buffers_in_order() {
+        let mut sched = Scheduler::new_default();
+        let state = sched.get_state();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        state.start_coroutine(move |c| {
+            let (stream, _) = listener.accept(c).unwrap();
+            stream.io(c).write_all(b"abcdefgh").unwrap();
+        });
+
+        let vp = state.prepare_coroutine(move |c| {
+            let stream = TcpStream::connect(c, addr).unwrap();
+            // Wait until all data has arrived.
+            let mut peeked = [0u8; 8];
+            while stream.peek(c, &mut peeked).unwrap() < 8 {
+                timer::sleep(c, Duration::from_millis(1));
+            }
+            assert_eq!(&peeked, b"abcdefgh");
+
+            let mut a = [0u8; 3];
+            let mut b = [0u8; 5];
+            let n = stream.read_vectored(c, &mut [IoSliceMut::new(&mut a), IoSliceMut::new(&mut b)]).unwrap();
+            assert_eq!(n, 8);
+            assert_eq!(&a, b"abc");
+            assert_eq!(&b, b"defgh");
+        });
+        sched.run_value_promise_to_end(vp).unwrap();
+    }
+
+    #[test]
+    fn socket_options_should_be_applied() {
+        let mut sched = Scheduler::new_default();
+        let state = sched.get_state();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let vp = state.prepare_coroutine(move |c| {
+            let stream = TcpStream::connect(c, addr).unwrap();
+
+            stream.set_nodelay(true).unwrap();
+            assert!(stream.nodelay().unwrap());
+
+            assert_eq!(stream.keepalive().unwrap(), None);
+            stream.set_keepalive(Some(Duration::from_secs(30))).unwrap();
+            assert_eq!(stream.keepalive().unwrap(), Some(Duration::from_secs(30)));
+            stream.set_keepalive(None).unwrap();
+            assert_eq!(stream.keepalive().unwrap(), None);
+
+            stream.set_ttl(42).unwrap();
+            assert_eq!(stream.ttl().unwrap(), 42);
+
+            stream.set_recv_buffer_size(65536).unwrap();
+            assert!(stream.recv_buffer_size().unwrap() >= 65536);
+        });
+        sched.run_value_promise_to_end(vp).unwrap();
+    }
+
+    #[test]
+    fn refused_connections_should_be_reported() {
+        let mut sched = Scheduler::new_default();
+        let state = sched.get_state();
+
+        // Find a port nobody listens on.
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        let vp = state.prepare_coroutine(move |c| {
+            TcpStream::connect(c, addr).err().unwrap().kind()
+        });
+        assert_eq!(sched.run_value_promise_to_end(vp).unwrap(), io::ErrorKind::ConnectionRefused);
+    }
+}
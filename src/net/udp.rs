@@ -0,0 +1,281 @@
+use std::io;
+use std::mem;
+use std::ptr;
+use std::net::{self, SocketAddr, ToSocketAddrs};
+use std::os::unix::io::{RawFd, AsRawFd, FromRawFd, IntoRawFd};
+use libc;
+use co::Yieldable;
+use reactor::Interest;
+use super::{each_addr, retry, cvt, bind_socket, socket_addr_to_raw, raw_to_socket_addr, setsockopt, getsockopt};
+
+/// A UDP socket.
+pub struct UdpSocket {
+    inner: net::UdpSocket
+}
+
+impl UdpSocket {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<UdpSocket> {
+        each_addr(addr, |addr| {
+            let fd = bind_socket(addr, libc::SOCK_DGRAM)?;
+            Ok(UdpSocket {
+                inner: unsafe { net::UdpSocket::from_raw_fd(fd) }
+            })
+        })
+    }
+
+    /// Takes over a socket from the standard library, switching it
+    /// to non-blocking mode.
+    pub fn from_std(inner: net::UdpSocket) -> io::Result<UdpSocket> {
+        inner.set_nonblocking(true)?;
+        Ok(UdpSocket {
+            inner: inner
+        })
+    }
+
+    /// Sets the default destination of `send` and the only source accepted
+    /// by `recv`.
+    pub fn connect<A: ToSocketAddrs>(&self, addr: A) -> io::Result<()> {
+        self.inner.connect(addr)
+    }
+
+    pub fn send_to(&self, c: &mut Yieldable, buf: &[u8], addr: &SocketAddr) -> io::Result<usize> {
+        retry(c, self.as_raw_fd(), Interest::Writable, || self.inner.send_to(buf, addr))
+    }
+
+    pub fn recv_from(&self, c: &mut Yieldable, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        retry(c, self.as_raw_fd(), Interest::Readable, || self.inner.recv_from(buf))
+    }
+
+    pub fn peek_from(&self, c: &mut Yieldable, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        retry(c, self.as_raw_fd(), Interest::Readable, || self.inner.peek_from(buf))
+    }
+
+    /// Sends to the connected address.
+    pub fn send(&self, c: &mut Yieldable, buf: &[u8]) -> io::Result<usize> {
+        retry(c, self.as_raw_fd(), Interest::Writable, || self.inner.send(buf))
+    }
+
+    /// Receives from the connected address.
+    pub fn recv(&self, c: &mut Yieldable, buf: &mut [u8]) -> io::Result<usize> {
+        retry(c, self.as_raw_fd(), Interest::Readable, || self.inner.recv(buf))
+    }
+
+    /// Receives up to `bufs.len()` datagrams with a single `recvmmsg` call,
+    /// suspending the current coroutine until at least one arrives.
+    ///
+    /// Returns the length and source address of each datagram received, in
+    /// the order of `bufs`. Datagrams longer than their buffer are truncated.
+    pub fn recv_many_from(&self, c: &mut Yieldable, bufs: &mut [&mut [u8]]) -> io::Result<Vec<(usize, SocketAddr)>> {
+        let fd = self.as_raw_fd();
+        let mut iovecs: Vec<libc::iovec> = bufs.iter_mut().map(|b| libc::iovec {
+            iov_base: b.as_mut_ptr() as *mut libc::c_void,
+            iov_len: b.len()
+        }).collect();
+        let mut addrs: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; bufs.len()];
+        let mut msgs: Vec<libc::mmsghdr> = iovecs.iter_mut().zip(addrs.iter_mut()).map(|(iov, addr)| unsafe {
+            let mut msg: libc::mmsghdr = mem::zeroed();
+            msg.msg_hdr.msg_name = addr as *mut _ as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            msg.msg_hdr.msg_iov = iov;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg
+        }).collect();
+
+        let n = retry(c, fd, Interest::Readable, || unsafe {
+            cvt(libc::recvmmsg(fd, msgs.as_mut_ptr(), msgs.len() as libc::c_uint, 0, ptr::null_mut()))
+        })? as usize;
+
+        msgs[..n].iter().zip(addrs.iter()).map(|(msg, addr)| {
+            Ok((msg.msg_len as usize, raw_to_socket_addr(addr)?))
+        }).collect()
+    }
+
+    /// Sends datagrams to their destinations with a single `sendmmsg` call,
+    /// suspending the current coroutine until the socket is writable.
+    ///
+    /// Returns the number of datagrams sent, which may be less than
+    /// `msgs.len()`.
+    pub fn send_many_to(&self, c: &mut Yieldable, msgs: &[(&[u8], SocketAddr)]) -> io::Result<usize> {
+        let addrs: Vec<(libc::sockaddr_storage, libc::socklen_t)> = msgs.iter()
+            .map(|m| socket_addr_to_raw(&m.1))
+            .collect();
+        let bufs: Vec<&[u8]> = msgs.iter().map(|m| m.0).collect();
+        self.send_many_impl(c, &bufs, Some(&addrs))
+    }
+
+    /// Sends datagrams to the connected address with a single `sendmmsg` call.
+    ///
+    /// Returns the number of datagrams sent, which may be less than
+    /// `bufs.len()`.
+    pub fn send_many(&self, c: &mut Yieldable, bufs: &[&[u8]]) -> io::Result<usize> {
+        self.send_many_impl(c, bufs, None)
+    }
+
+    fn send_many_impl(&self, c: &mut Yieldable, bufs: &[&[u8]], addrs: Option<&[(libc::sockaddr_storage, libc::socklen_t)]>) -> io::Result<usize> {
+        let fd = self.as_raw_fd();
+        let mut iovecs: Vec<libc::iovec> = bufs.iter().map(|b| libc::iovec {
+            iov_base: b.as_ptr() as *mut libc::c_void,
+            iov_len: b.len()
+        }).collect();
+        let mut msgs: Vec<libc::mmsghdr> = iovecs.iter_mut().enumerate().map(|(i, iov)| unsafe {
+            let mut msg: libc::mmsghdr = mem::zeroed();
+            if let Some(addrs) = addrs {
+                msg.msg_hdr.msg_name = &addrs[i].0 as *const _ as *mut libc::c_void;
+                msg.msg_hdr.msg_namelen = addrs[i].1;
+            }
+            msg.msg_hdr.msg_iov = iov;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg
+        }).collect();
+
+        retry(c, fd, Interest::Writable, || unsafe {
+            cvt(libc::sendmmsg(fd, msgs.as_mut_ptr(), msgs.len() as libc::c_uint, 0))
+        }).map(|n| n as usize)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    pub fn set_broadcast(&self, broadcast: bool) -> io::Result<()> {
+        self.inner.set_broadcast(broadcast)
+    }
+
+    pub fn broadcast(&self) -> io::Result<bool> {
+        self.inner.broadcast()
+    }
+
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        self.inner.set_ttl(ttl)
+    }
+
+    pub fn ttl(&self) -> io::Result<u32> {
+        self.inner.ttl()
+    }
+
+    pub fn set_recv_buffer_size(&self, size: usize) -> io::Result<()> {
+        setsockopt::<libc::c_int>(self.as_raw_fd(), libc::SOL_SOCKET, libc::SO_RCVBUF, size as libc::c_int)
+    }
+
+    pub fn recv_buffer_size(&self) -> io::Result<usize> {
+        getsockopt::<libc::c_int>(self.as_raw_fd(), libc::SOL_SOCKET, libc::SO_RCVBUF).map(|v| v as usize)
+    }
+
+    pub fn set_send_buffer_size(&self, size: usize) -> io::Result<()> {
+        setsockopt::<libc::c_int>(self.as_raw_fd(), libc::SOL_SOCKET, libc::SO_SNDBUF, size as libc::c_int)
+    }
+
+    pub fn send_buffer_size(&self) -> io::Result<usize> {
+        getsockopt::<libc::c_int>(self.as_raw_fd(), libc::SOL_SOCKET, libc::SO_SNDBUF).map(|v| v as usize)
+    }
+
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        self.inner.take_error()
+    }
+}
+
+impl AsRawFd for UdpSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for UdpSocket {
+    fn into_raw_fd(self) -> RawFd {
+        self.inner.into_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scheduler::Scheduler;
+
+    #[test]
+    fn datagrams_should_be_exchanged() {
+        let mut sched = Scheduler::new_default();
+        let state = sched.get_state();
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        state.start_coroutine(move |c| {
+            let mut buf = [0u8; 64];
+            for _ in 0..2 {
+                let (n, peer) = server.recv_from(c, &mut buf).unwrap();
+                server.send_to(c, &buf[..n], &peer).unwrap();
+            }
+        });
+
+        let vp = state.prepare_coroutine(move |c| {
+            let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let mut buf = [0u8; 64];
+
+            client.send_to(c, b"ping", &server_addr).unwrap();
+            let (n, peer) = client.recv_from(c, &mut buf).unwrap();
+            assert_eq!(&buf[..n], b"ping");
+            assert_eq!(peer, server_addr);
+
+            client.connect(server_addr).unwrap();
+            assert_eq!(client.peer_addr().unwrap(), server_addr);
+            client.send(c, b"pong").unwrap();
+            let n = client.recv(c, &mut buf).unwrap();
+            assert_eq!(&buf[..n], b"pong");
+        });
+        sched.run_value_promise_to_end(vp).unwrap();
+    }
+
+    #[test]
+    fn datagrams_should_be_batched() {
+        const N: usize = 32;
+
+        let mut sched = Scheduler::new_default();
+        let state = sched.get_state();
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client_addr = client.local_addr().unwrap();
+
+        let receiver = state.prepare_coroutine(move |c| {
+            let mut storage = [[0u8; 16]; 8];
+            let mut received: Vec<Vec<u8>> = Vec::new();
+            while received.len() < N {
+                let mut bufs: Vec<&mut [u8]> = storage.iter_mut().map(|b| &mut b[..]).collect();
+                let results = server.recv_many_from(c, &mut bufs).unwrap();
+                assert!(!results.is_empty());
+                for (buf, &(n, addr)) in bufs.iter().zip(results.iter()) {
+                    assert_eq!(addr, client_addr);
+                    received.push(buf[..n].to_vec());
+                }
+            }
+            received
+        });
+
+        state.start_coroutine(move |c| {
+            let payloads: Vec<String> = (0..N).map(|i| format!("msg {}", i)).collect();
+
+            // Half to an explicit destination, half over a connected socket.
+            let msgs: Vec<(&[u8], SocketAddr)> = payloads[..N / 2].iter()
+                .map(|p| (p.as_bytes(), server_addr))
+                .collect();
+            let mut sent: usize = 0;
+            while sent < msgs.len() {
+                sent += client.send_many_to(c, &msgs[sent..]).unwrap();
+            }
+
+            client.connect(server_addr).unwrap();
+            let bufs: Vec<&[u8]> = payloads[N / 2..].iter().map(|p| p.as_bytes()).collect();
+            let mut sent: usize = 0;
+            while sent < bufs.len() {
+                sent += client.send_many(c, &bufs[sent..]).unwrap();
+            }
+        });
+
+        let received = sched.run_value_promise_to_end(receiver).unwrap();
+        let expected: Vec<Vec<u8>> = (0..N).map(|i| format!("msg {}", i).into_bytes()).collect();
+        assert_eq!(received, expected);
+    }
+}
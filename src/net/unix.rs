@@ -0,0 +1,570 @@
+use std::io::{self, Read, Write, IoSlice, IoSliceMut};
+use std::mem;
+use std::ptr;
+use std::path::Path;
+use std::net::Shutdown;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{self, SocketAddr};
+use std::os::unix::io::{RawFd, AsRawFd, FromRawFd, IntoRawFd};
+use libc;
+use co::Yieldable;
+use reactor::Interest;
+use super::{retry, cvt, cvt_size, new_socket, getsockopt};
+
+/// Credentials of the process on the other end of a Unix socket.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct UCred {
+    pub pid: libc::pid_t,
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t
+}
+
+fn sockaddr_un(path: &Path) -> io::Result<(libc::sockaddr_un, libc::socklen_t)> {
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+
+    let bytes = path.as_os_str().as_bytes();
+    if bytes.contains(&0) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "paths must not contain interior null bytes"));
+    }
+    // Leave room for the terminating null byte.
+    if bytes.len() >= addr.sun_path.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "path must be shorter than SUN_LEN"));
+    }
+    for (dst, src) in addr.sun_path.iter_mut().zip(bytes.iter()) {
+        *dst = *src as libc::c_char;
+    }
+
+    let base = &addr as *const _ as usize;
+    let path_offset = &addr.sun_path as *const _ as usize - base;
+    Ok((addr, (path_offset + bytes.len() + 1) as libc::socklen_t))
+}
+
+fn peer_cred(fd: RawFd) -> io::Result<UCred> {
+    let cred: libc::ucred = getsockopt(fd, libc::SOL_SOCKET, libc::SO_PEERCRED)?;
+    Ok(UCred {
+        pid: cred.pid,
+        uid: cred.uid,
+        gid: cred.gid
+    })
+}
+
+/// Sends `buf` along with `fds` in a single `SCM_RIGHTS` message.
+fn send_with_fds(fd: RawFd, buf: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+    unsafe {
+        let fds_len = mem::size_of_val(fds) as libc::c_uint;
+        let mut control: Vec<u64> = vec![0; (libc::CMSG_SPACE(fds_len) as usize).div_ceil(8)];
+
+        let mut iov = libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len()
+        };
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+
+        if !fds.is_empty() {
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = libc::CMSG_SPACE(fds_len) as _;
+
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+            ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut RawFd, fds.len());
+        }
+
+        cvt_size(libc::sendmsg(fd, &msg, libc::MSG_NOSIGNAL))
+    }
+}
+
+/// Receives into `buf`, storing file descriptors passed with `SCM_RIGHTS`
+/// in `fds`.
+///
+/// Returns the number of bytes and descriptors received.
+fn recv_with_fds(fd: RawFd, buf: &mut [u8], fds: &mut [RawFd]) -> io::Result<(usize, usize)> {
+    unsafe {
+        let fds_len = mem::size_of_val(fds) as libc::c_uint;
+        let mut control: Vec<u64> = vec![0; (libc::CMSG_SPACE(fds_len) as usize).div_ceil(8)];
+
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len()
+        };
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        if !fds.is_empty() {
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = libc::CMSG_SPACE(fds_len) as _;
+        }
+
+        let n = cvt_size(libc::recvmsg(fd, &mut msg, libc::MSG_CMSG_CLOEXEC))?;
+
+        let mut n_fds: usize = 0;
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let data_len = (*cmsg).cmsg_len as usize - (data as usize - cmsg as usize);
+                for i in 0..data_len / mem::size_of::<RawFd>() {
+                    let received = ptr::read_unaligned(data.add(i));
+                    if n_fds < fds.len() {
+                        fds[n_fds] = received;
+                        n_fds += 1;
+                    } else {
+                        libc::close(received);
+                    }
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+
+        Ok((n, n_fds))
+    }
+}
+
+/// A Unix domain socket server.
+pub struct UnixListener {
+    inner: net::UnixListener
+}
+
+impl UnixListener {
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<UnixListener> {
+        Self::from_std(net::UnixListener::bind(path)?)
+    }
+
+    /// Takes over a listener from the standard library, switching it
+    /// to non-blocking mode.
+    pub fn from_std(inner: net::UnixListener) -> io::Result<UnixListener> {
+        inner.set_nonblocking(true)?;
+        Ok(UnixListener {
+            inner: inner
+        })
+    }
+
+    /// Accepts a new connection, suspending the current coroutine until
+    /// one arrives.
+    pub fn accept(&self, c: &mut Yieldable) -> io::Result<(UnixStream, SocketAddr)> {
+        let (stream, addr) = retry(c, self.as_raw_fd(), Interest::Readable, || self.inner.accept())?;
+        Ok((UnixStream::from_std(stream)?, addr))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        self.inner.take_error()
+    }
+}
+
+impl AsRawFd for UnixListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for UnixListener {
+    fn into_raw_fd(self) -> RawFd {
+        self.inner.into_raw_fd()
+    }
+}
+
+/// A Unix domain stream socket.
+///
+/// All operations take `&self`, so a stream shared between two coroutines
+/// can be read by one and written by the other at the same time.
+pub struct UnixStream {
+    inner: net::UnixStream
+}
+
+impl UnixStream {
+    /// Connects to the socket at `path`, suspending the current coroutine
+    /// while the listener's backlog is full.
+    pub fn connect<P: AsRef<Path>>(c: &mut Yieldable, path: P) -> io::Result<UnixStream> {
+        let (addr, len) = sockaddr_un(path.as_ref())?;
+        let fd = new_socket(libc::AF_UNIX, libc::SOCK_STREAM)?;
+        let stream = UnixStream {
+            inner: unsafe { net::UnixStream::from_raw_fd(fd) }
+        };
+
+        retry(c, fd, Interest::Writable, || unsafe {
+            cvt(libc::connect(fd, &addr as *const _ as *const libc::sockaddr, len))
+        })?;
+        Ok(stream)
+    }
+
+    /// Creates a pair of connected streams.
+    pub fn pair() -> io::Result<(UnixStream, UnixStream)> {
+        let (a, b) = net::UnixStream::pair()?;
+        Ok((UnixStream::from_std(a)?, UnixStream::from_std(b)?))
+    }
+
+    /// Takes over a stream from the standard library, switching it
+    /// to non-blocking mode.
+    pub fn from_std(inner: net::UnixStream) -> io::Result<UnixStream> {
+        inner.set_nonblocking(true)?;
+        Ok(UnixStream {
+            inner: inner
+        })
+    }
+
+    /// Returns an adapter implementing `Read` and `Write` for use by the
+    /// coroutine `c`.
+    pub fn io<'a>(&'a self, c: &'a mut Yieldable) -> UnixStreamIo<'a> {
+        UnixStreamIo {
+            stream: self,
+            co: c
+        }
+    }
+
+    pub fn read(&self, c: &mut Yieldable, buf: &mut [u8]) -> io::Result<usize> {
+        retry(c, self.as_raw_fd(), Interest::Readable, || (&self.inner).read(buf))
+    }
+
+    pub fn read_vectored(&self, c: &mut Yieldable, bufs: &mut [IoSliceMut]) -> io::Result<usize> {
+        retry(c, self.as_raw_fd(), Interest::Readable, || (&self.inner).read_vectored(bufs))
+    }
+
+    pub fn write(&self, c: &mut Yieldable, buf: &[u8]) -> io::Result<usize> {
+        retry(c, self.as_raw_fd(), Interest::Writable, || (&self.inner).write(buf))
+    }
+
+    pub fn write_vectored(&self, c: &mut Yieldable, bufs: &[IoSlice]) -> io::Result<usize> {
+        retry(c, self.as_raw_fd(), Interest::Writable, || (&self.inner).write_vectored(bufs))
+    }
+
+    /// Writes `buf` and passes `fds` to the peer.
+    ///
+    /// The descriptors are attached to the first byte written, so `buf`
+    /// must not be empty.
+    pub fn send_fds(&self, c: &mut Yieldable, buf: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+        let fd = self.as_raw_fd();
+        retry(c, fd, Interest::Writable, || send_with_fds(fd, buf, fds))
+    }
+
+    /// Reads into `buf`, storing descriptors passed by the peer in `fds`.
+    ///
+    /// Returns the number of bytes and descriptors received. The caller owns
+    /// the received descriptors. Descriptors that don't fit into `fds` are
+    /// closed.
+    pub fn recv_fds(&self, c: &mut Yieldable, buf: &mut [u8], fds: &mut [RawFd]) -> io::Result<(usize, usize)> {
+        let fd = self.as_raw_fd();
+        retry(c, fd, Interest::Readable, || recv_with_fds(fd, buf, fds))
+    }
+
+    pub fn peer_cred(&self) -> io::Result<UCred> {
+        peer_cred(self.as_raw_fd())
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        self.inner.take_error()
+    }
+}
+
+impl AsRawFd for UnixStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for UnixStream {
+    fn into_raw_fd(self) -> RawFd {
+        self.inner.into_raw_fd()
+    }
+}
+
+/// A `UnixStream` bound to the coroutine using it.
+pub struct UnixStreamIo<'a> {
+    stream: &'a UnixStream,
+    co: &'a mut Yieldable
+}
+
+impl<'a> Read for UnixStreamIo<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(self.co, buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut]) -> io::Result<usize> {
+        self.stream.read_vectored(self.co, bufs)
+    }
+}
+
+impl<'a> Write for UnixStreamIo<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(self.co, buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        self.stream.write_vectored(self.co, bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A Unix domain datagram socket.
+pub struct UnixDatagram {
+    inner: net::UnixDatagram
+}
+
+impl UnixDatagram {
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<UnixDatagram> {
+        Self::from_std(net::UnixDatagram::bind(path)?)
+    }
+
+    /// Creates a socket not bound to any address.
+    pub fn unbound() -> io::Result<UnixDatagram> {
+        Self::from_std(net::UnixDatagram::unbound()?)
+    }
+
+    /// Creates a pair of connected sockets.
+    pub fn pair() -> io::Result<(UnixDatagram, UnixDatagram)> {
+        let (a, b) = net::UnixDatagram::pair()?;
+        Ok((UnixDatagram::from_std(a)?, UnixDatagram::from_std(b)?))
+    }
+
+    /// Takes over a socket from the standard library, switching it
+    /// to non-blocking mode.
+    pub fn from_std(inner: net::UnixDatagram) -> io::Result<UnixDatagram> {
+        inner.set_nonblocking(true)?;
+        Ok(UnixDatagram {
+            inner: inner
+        })
+    }
+
+    /// Sets the default destination of `send` and the only source accepted
+    /// by `recv`.
+    pub fn connect<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.inner.connect(path)
+    }
+
+    pub fn send_to<P: AsRef<Path>>(&self, c: &mut Yieldable, buf: &[u8], path: P) -> io::Result<usize> {
+        let path = path.as_ref();
+        retry(c, self.as_raw_fd(), Interest::Writable, || self.inner.send_to(buf, path))
+    }
+
+    pub fn recv_from(&self, c: &mut Yieldable, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        retry(c, self.as_raw_fd(), Interest::Readable, || self.inner.recv_from(buf))
+    }
+
+    /// Sends to the connected address.
+    pub fn send(&self, c: &mut Yieldable, buf: &[u8]) -> io::Result<usize> {
+        retry(c, self.as_raw_fd(), Interest::Writable, || self.inner.send(buf))
+    }
+
+    /// Receives from the connected address.
+    pub fn recv(&self, c: &mut Yieldable, buf: &mut [u8]) -> io::Result<usize> {
+        retry(c, self.as_raw_fd(), Interest::Readable, || self.inner.recv(buf))
+    }
+
+    /// Sends `buf` to the connected address, passing `fds` along with it.
+    pub fn send_fds(&self, c: &mut Yieldable, buf: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+        let fd = self.as_raw_fd();
+        retry(c, fd, Interest::Writable, || send_with_fds(fd, buf, fds))
+    }
+
+    /// Receives a datagram into `buf`, storing descriptors passed along with
+    /// it in `fds`.
+    ///
+    /// Returns the number of bytes and descriptors received. The caller owns
+    /// the received descriptors. Descriptors that don't fit into `fds` are
+    /// closed.
+    pub fn recv_fds(&self, c: &mut Yieldable, buf: &mut [u8], fds: &mut [RawFd]) -> io::Result<(usize, usize)> {
+        let fd = self.as_raw_fd();
+        retry(c, fd, Interest::Readable, || recv_with_fds(fd, buf, fds))
+    }
+
+    pub fn peer_cred(&self) -> io::Result<UCred> {
+        peer_cred(self.as_raw_fd())
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        self.inner.take_error()
+    }
+}
+
+impl AsRawFd for UnixDatagram {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for UnixDatagram {
+    fn into_raw_fd(self) -> RawFd {
+        self.inner.into_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use scheduler::Scheduler;
+
+    fn temp_path(name: &str) -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = ::std::env::temp_dir().join(format!(
+            "liblightning-{}-{}-{}.sock",
+            name,
+            unsafe { libc::getpid() },
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn pipe() -> (RawFd, RawFd) {
+        let mut fds: [RawFd; 2] = [0; 2];
+        unsafe {
+            assert_eq!(libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC), 0);
+        }
+        (fds[0], fds[1])
+    }
+
+    #[test]
+    fn streams_should_be_accepted_and_connected() {
+        let mut sched = Scheduler::new_default();
+        let state = sched.get_state();
+        let path = temp_path("stream");
+        let listener = UnixListener::bind(&path).unwrap();
+
+        state.start_coroutine(move |c| {
+            for _ in 0..16 {
+                let (stream, _) = listener.accept(c).unwrap();
+                let cred = stream.peer_cred().unwrap();
+                assert_eq!(cred.pid, unsafe { libc::getpid() });
+                assert_eq!(cred.uid, unsafe { libc::getuid() });
+
+                let mut buf = Vec::new();
+                stream.io(c).read_to_end(&mut buf).unwrap();
+                stream.io(c).write_all(&buf).unwrap();
+            }
+        });
+
+        let path2 = path.clone();
+        let vp = state.prepare_coroutine(move |c| {
+            for i in 0..16 {
+                let stream = UnixStream::connect(c, &path2).unwrap();
+                let msg = format!("hello {}", i);
+                stream.io(c).write_all(msg.as_bytes()).unwrap();
+                stream.shutdown(Shutdown::Write).unwrap();
+
+                let mut echoed = String::new();
+                stream.io(c).read_to_string(&mut echoed).unwrap();
+                assert_eq!(echoed, msg);
+            }
+        });
+        sched.run_value_promise_to_end(vp).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn fds_should_be_passed_over_streams() {
+        let mut sched = Scheduler::new_default();
+        let state = sched.get_state();
+        let (a, b) = UnixStream::pair().unwrap();
+
+        state.start_coroutine(move |c| {
+            let (rfd, wfd) = pipe();
+            unsafe {
+                assert_eq!(libc::write(wfd, b"via pipe".as_ptr() as *const libc::c_void, 8), 8);
+                libc::close(wfd);
+            }
+            assert_eq!(a.send_fds(c, b"x", &[rfd]).unwrap(), 1);
+            unsafe {
+                libc::close(rfd);
+            }
+        });
+
+        let vp = state.prepare_coroutine(move |c| {
+            let mut buf = [0u8; 16];
+            let mut fds: [RawFd; 4] = [-1; 4];
+            let (n, n_fds) = b.recv_fds(c, &mut buf, &mut fds).unwrap();
+            assert_eq!(&buf[..n], b"x");
+            assert_eq!(n_fds, 1);
+
+            let mut pipe = unsafe { ::std::fs::File::from_raw_fd(fds[0]) };
+            let mut received = String::new();
+            pipe.read_to_string(&mut received).unwrap();
+            assert_eq!(received, "via pipe");
+        });
+        sched.run_value_promise_to_end(vp).unwrap();
+    }
+
+    #[test]
+    fn datagrams_should_be_exchanged() {
+        let mut sched = Scheduler::new_default();
+        let state = sched.get_state();
+        let server_path = temp_path("dgram-server");
+        let client_path = temp_path("dgram-client");
+        let server = UnixDatagram::bind(&server_path).unwrap();
+
+        state.start_coroutine(move |c| {
+            let mut buf = [0u8; 64];
+            let (n, peer) = server.recv_from(c, &mut buf).unwrap();
+            server.send_to(c, &buf[..n], peer.as_pathname().unwrap()).unwrap();
+        });
+
+        let (server_path2, client_path2) = (server_path.clone(), client_path.clone());
+        let vp = state.prepare_coroutine(move |c| {
+            let client = UnixDatagram::bind(&client_path2).unwrap();
+            client.send_to(c, b"ping", &server_path2).unwrap();
+
+            let mut buf = [0u8; 64];
+            let (n, peer) = client.recv_from(c, &mut buf).unwrap();
+            assert_eq!(&buf[..n], b"ping");
+            assert_eq!(peer.as_pathname(), Some(server_path2.as_path()));
+
+            // Pass an fd over a connected pair.
+            let (a, b) = UnixDatagram::pair().unwrap();
+            let (rfd, wfd) = pipe();
+            a.send_fds(c, b"fd", &[wfd]).unwrap();
+            unsafe {
+                libc::close(wfd);
+            }
+            let mut fds: [RawFd; 1] = [-1];
+            let (n, n_fds) = b.recv_fds(c, &mut buf, &mut fds).unwrap();
+            assert_eq!((&buf[..n], n_fds), (&b"fd"[..], 1));
+            assert_eq!(b.peer_cred().unwrap().pid, unsafe { libc::getpid() });
+            unsafe {
+                assert_eq!(libc::write(fds[0], b"!".as_ptr() as *const libc::c_void, 1), 1);
+                assert_eq!(libc::read(rfd, buf.as_mut_ptr() as *mut libc::c_void, 1), 1);
+                libc::close(fds[0]);
+                libc::close(rfd);
+            }
+            assert_eq!(buf[0], b'!');
+        });
+        sched.run_value_promise_to_end(vp).unwrap();
+        fs::remove_file(&server_path).unwrap();
+        fs::remove_file(&client_path).unwrap();
+    }
+}
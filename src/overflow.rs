@@ -0,0 +1,203 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::cell::RefCell;
+use std::fmt::{self, Write};
+use std::mem;
+use std::ptr::null_mut;
+use std::os::raw::c_void;
+use libc;
+use platform;
+
+const SIGNAL_STACK_SIZE: usize = 65536;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    // Guard page start -> size of the whole mapping (including the guard page).
+    static ref KNOWN_STACKS: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+    static ref PREV_ACTION: Mutex<Option<libc::sigaction>> = Mutex::new(None);
+}
+
+struct SignalStack {
+    mem: Option<*mut [u8]>
+}
+
+impl Drop for SignalStack {
+    fn drop(&mut self) {
+        if let Some(mem) = self.mem.take() {
+            unsafe {
+                let ss = libc::stack_t {
+                    ss_sp: null_mut(),
+                    ss_flags: libc::SS_DISABLE,
+                    ss_size: 0
+                };
+                libc::sigaltstack(&ss, null_mut());
+                let _ = platform::free_stack(mem);
+            }
+        }
+    }
+}
+
+thread_local! {
+    static SIGNAL_STACK: RefCell<Option<SignalStack>> = const { RefCell::new(None) };
+}
+
+/// Enables coroutine stack overflow detection.
+///
+/// Installs a SIGSEGV handler running on an alternate signal stack. When
+/// the faulting address is inside the guard page of a coroutine stack,
+/// a diagnostic report is printed and the process is aborted. Other faults
+/// are passed on to the previously installed handler.
+///
+/// Only stacks created after the first call are tracked. Must be called on
+/// every thread that runs coroutines, so that the thread gets a signal stack.
+pub fn install_handler() {
+    {
+        let mut prev = PREV_ACTION.lock().unwrap();
+        if prev.is_none() {
+            unsafe {
+                let mut action: libc::sigaction = mem::zeroed();
+                action.sa_sigaction = handle_sigsegv as *const () as usize;
+                action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+                libc::sigemptyset(&mut action.sa_mask);
+
+                let mut old: libc::sigaction = mem::zeroed();
+                if libc::sigaction(libc::SIGSEGV, &action, &mut old) != 0 {
+                    panic!("sigaction failed");
+                }
+                *prev = Some(old);
+            }
+            ENABLED.store(true, Ordering::SeqCst);
+        }
+    }
+
+    SIGNAL_STACK.with(|s| {
+        let mut s = s.borrow_mut();
+        if s.is_some() {
+            return;
+        }
+
+        unsafe {
+            let mut current: libc::stack_t = mem::zeroed();
+            libc::sigaltstack(null_mut(), &mut current);
+
+            // The standard library may have set one up already.
+            if current.ss_flags & libc::SS_DISABLE == 0 {
+                *s = Some(SignalStack { mem: None });
+                return;
+            }
+
+            let mem = platform::setup_stack(SIGNAL_STACK_SIZE).unwrap_or_else(|e| {
+                panic!("Unable to allocate signal stack: {}", e);
+            });
+            let ss = libc::stack_t {
+                ss_sp: &mut (&mut *mem)[0] as *mut u8 as *mut c_void,
+                ss_flags: 0,
+                ss_size: SIGNAL_STACK_SIZE
+            };
+            if libc::sigaltstack(&ss, null_mut()) != 0 {
+                panic!("sigaltstack failed");
+            }
+            *s = Some(SignalStack { mem: Some(mem) });
+        }
+    });
+}
+
+pub(crate) fn register_stack(mem: *mut [u8]) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let mem = unsafe { &mut *mem };
+    KNOWN_STACKS.lock().unwrap().insert(&mut mem[0] as *mut u8 as usize, mem.len());
+}
+
+pub(crate) fn unregister_stack(mem: *mut [u8]) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let mem = unsafe { &mut *mem };
+    KNOWN_STACKS.lock().unwrap().remove(&(&mut mem[0] as *mut u8 as usize));
+}
+
+/// Returns the start and size of the stack whose guard page contains `addr`.
+fn find_overflowed_stack(addr: usize) -> Option<(usize, usize)> {
+    // Don't block inside the signal handler.
+    let stacks = match KNOWN_STACKS.try_lock() {
+        Ok(v) => v,
+        Err(_) => return None
+    };
+    match stacks.range(..addr + 1).next_back() {
+        Some((&start, &size)) if addr < start + *platform::PAGE_SIZE => Some((start, size)),
+        _ => None
+    }
+}
+
+/// A fixed-size buffer for formatting without allocating.
+struct MessageBuffer {
+    buf: [u8; 256],
+    len: usize
+}
+
+impl Write for MessageBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let s = s.as_bytes();
+        let n = ::std::cmp::min(s.len(), self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+extern "C" fn handle_sigsegv(sig: libc::c_int, info: *mut libc::siginfo_t, ctx: *mut c_void) {
+    let addr = unsafe { (*info).si_addr() } as usize;
+
+    if let Some((start, size)) = find_overflowed_stack(addr) {
+        let page_size = *platform::PAGE_SIZE;
+        let mut msg = MessageBuffer {
+            buf: [0; 256],
+            len: 0
+        };
+        let _ = writeln!(
+            msg,
+            "coroutine stack overflow: fault address {:#x}, stack {:#x}-{:#x} ({} bytes)",
+            addr,
+            start + page_size,
+            start + size,
+            size - page_size
+        );
+        unsafe {
+            libc::write(2, msg.buf.as_ptr() as *const c_void, msg.len);
+            libc::abort();
+        }
+    }
+
+    // Not ours. Hand the signal over to the previous handler.
+    let prev = match PREV_ACTION.try_lock() {
+        Ok(v) => *v,
+        Err(_) => None
+    };
+    unsafe {
+        match prev {
+            Some(ref prev) if prev.sa_sigaction != libc::SIG_DFL && prev.sa_sigaction != libc::SIG_IGN => {
+                if prev.sa_flags & libc::SA_SIGINFO != 0 {
+                    let f: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut c_void) = mem::transmute(prev.sa_sigaction);
+                    f(sig, info, ctx);
+                } else {
+                    let f: extern "C" fn(libc::c_int) = mem::transmute(prev.sa_sigaction);
+                    f(sig);
+                }
+            },
+            _ => {
+                // Re-executing the faulting instruction raises the signal again
+                // with the default action.
+                let mut action: libc::sigaction = mem::zeroed();
+                action.sa_sigaction = libc::SIG_DFL;
+                libc::sigaction(libc::SIGSEGV, &action, null_mut());
+            }
+        }
+    }
+}
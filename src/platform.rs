@@ -1,5 +1,6 @@
 use std::ptr::null_mut;
 use libc;
+use error::{Error, ErrorKind};
 
 lazy_static! {
     pub static ref PAGE_SIZE: usize = {
@@ -9,18 +10,23 @@ lazy_static! {
     };
 }
 
-fn round_up_stack_size(size: usize) -> usize {
+pub fn round_up_stack_size(size: usize) -> usize {
+    checked_round_up_stack_size(size).expect("stack size overflow")
+}
+
+/// Rounds `size` up to whole pages, or returns `None` on overflow.
+pub fn checked_round_up_stack_size(size: usize) -> Option<usize> {
     let page_size: usize = *PAGE_SIZE;
 
     let rem = size % page_size;
     if rem > 0 {
-        size - rem + page_size
+        (size - rem).checked_add(page_size)
     } else {
-        size
+        Some(size)
     }
 }
 
-pub unsafe fn setup_stack_guard_page(stack: *mut [u8]) {
+pub unsafe fn setup_stack_guard_page(stack: *mut [u8]) -> Result<(), Error> {
     let stack = &mut *stack;
     let ret = libc::mprotect(
         &mut stack[0] as *mut u8 as *mut libc::c_void,
@@ -28,42 +34,94 @@ pub unsafe fn setup_stack_guard_page(stack: *mut [u8]) {
         libc::PROT_NONE
     );
     if ret != 0 {
-        panic!("mprotect failed");
+        return Err(Error::last_os_error(ErrorKind::GuardPage));
     }
+    Ok(())
+}
+
+pub fn setup_stack(size: usize) -> Result<*mut [u8], Error> {
+    map_stack(size, 0)
+}
+
+/// Like `setup_stack`, but does not reserve swap space for the mapping.
+///
+/// Pages are only committed when they are touched.
+pub fn setup_stack_noreserve(size: usize) -> Result<*mut [u8], Error> {
+    map_stack(size, libc::MAP_NORESERVE)
 }
 
-pub fn setup_stack(size: usize) -> *mut [u8] {
+fn map_stack(size: usize, extra_flags: libc::c_int) -> Result<*mut [u8], Error> {
     if size == 0 {
-        panic!("stack size must be greater than zero");
+        return Err(Error::new(ErrorKind::InvalidStackSize, libc::EINVAL));
     }
 
-    let size = round_up_stack_size(size);
+    let size = checked_round_up_stack_size(size)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidStackSize, libc::EOVERFLOW))?;
 
     let stack = unsafe { libc::mmap(
         null_mut(),
         size,
         libc::PROT_READ | libc::PROT_WRITE,
-        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | extra_flags,
         -1,
         0
-    ) as *mut u8 };
-    if stack.is_null() {
-        panic!("mmap failed");
+    ) };
+    if stack == libc::MAP_FAILED {
+        return Err(Error::last_os_error(ErrorKind::StackAllocation));
     }
 
     unsafe {
-        ::std::slice::from_raw_parts_mut(stack, size) as *mut [u8]
+        Ok(::std::slice::from_raw_parts_mut(stack as *mut u8, size) as *mut [u8])
     }
 }
 
-pub unsafe fn free_stack(stack: *mut [u8]) {
+/// Gives the pages of `stack` back to the OS. The mapping stays valid.
+///
+/// With `lazy`, the kernel only reclaims the pages under memory pressure
+/// (`MADV_FREE`). Otherwise they are dropped immediately (`MADV_DONTNEED`).
+pub unsafe fn discard_stack_pages(stack: *mut [u8], lazy: bool) -> Result<(), Error> {
+    let stack = &mut *stack;
+    let ptr = &mut stack[0] as *mut u8 as *mut libc::c_void;
+
+    if lazy && libc::madvise(ptr, stack.len(), libc::MADV_FREE) == 0 {
+        return Ok(());
+    }
+
+    // MADV_FREE is not supported before Linux 4.5.
+    let ret = libc::madvise(ptr, stack.len(), libc::MADV_DONTNEED);
+    if ret != 0 {
+        return Err(Error::last_os_error(ErrorKind::PageDiscard));
+    }
+    Ok(())
+}
+
+/// Returns the number of bytes of `stack` that are resident in memory.
+pub unsafe fn resident_size(stack: *mut [u8]) -> Result<usize, Error> {
+    let stack = &mut *stack;
+    let page_size: usize = *PAGE_SIZE;
+    let mut pages: Vec<libc::c_uchar> = vec![0; stack.len() / page_size];
+
+    let ret = libc::mincore(
+        &mut stack[0] as *mut u8 as *mut libc::c_void,
+        stack.len(),
+        pages.as_mut_ptr()
+    );
+    if ret != 0 {
+        return Err(Error::last_os_error(ErrorKind::ResidencyQuery));
+    }
+
+    Ok(pages.iter().filter(|p| **p & 1 != 0).count() * page_size)
+}
+
+pub unsafe fn free_stack(stack: *mut [u8]) -> Result<(), Error> {
     let stack = &mut *stack;
     let ptr = &mut stack[0] as *mut u8;
     let size = stack.len();
     let ret = libc::munmap(ptr as *mut libc::c_void, size);
     if ret != 0 {
-        panic!("munmap failed");
+        return Err(Error::last_os_error(ErrorKind::StackRelease));
     }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -84,23 +142,53 @@ mod tests {
     fn stack_should_be_writable() {
         const LEN: usize = 20000;
 
-        let stack = setup_stack(LEN);
+        let stack = setup_stack(LEN).unwrap();
         for i in 0..LEN {
             unsafe {
                 (&mut *stack)[i] = 42;
             }
         }
         unsafe {
-            free_stack(stack);
+            free_stack(stack).unwrap();
+        }
+    }
+
+    #[test]
+    fn noreserve_stack_should_be_committed_lazily() {
+        let page_size: usize = *PAGE_SIZE;
+        let stack = setup_stack_noreserve(page_size * 16).unwrap();
+        unsafe {
+            assert_eq!(resident_size(stack), Ok(0));
+            (&mut *stack)[page_size * 3] = 42;
+            assert_eq!(resident_size(stack), Ok(page_size));
+            free_stack(stack).unwrap();
         }
     }
 
     #[test]
     fn setup_stack_guard_page_should_succeed() {
-        let stack = setup_stack(*PAGE_SIZE);
+        let stack = setup_stack(*PAGE_SIZE).unwrap();
         unsafe {
-            setup_stack_guard_page(stack);
-            free_stack(stack);
+            setup_stack_guard_page(stack).unwrap();
+            free_stack(stack).unwrap();
         }
     }
+
+    #[test]
+    fn huge_stack_allocation_should_fail_with_errno() {
+        let e = setup_stack(1 << 60).err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::StackAllocation);
+        assert_eq!(e.errno(), libc::ENOMEM);
+    }
+
+    #[test]
+    fn invalid_stack_sizes_should_fail() {
+        let e = setup_stack(0).err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::InvalidStackSize);
+        assert_eq!(e.errno(), libc::EINVAL);
+
+        let e = setup_stack(usize::MAX).err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::InvalidStackSize);
+        assert_eq!(e.errno(), libc::EOVERFLOW);
+    }
 }
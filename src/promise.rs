@@ -1,7 +1,11 @@
 use std::cell::UnsafeCell;
-use co::{CommonCoState, SendableCoState};
+use std::time::Instant;
+use std::os::unix::io::RawFd;
+use co::SendableCoState;
+use mpsc_queue::CoBox;
 use scheduler::{SharedSchedState, SyncSchedState};
 use invoke_box::OnceInvokeBox;
+use reactor::Interest;
 
 pub enum PromiseState {
     Waiting(OnceInvokeBox<NotifyHandle, ()>),
@@ -20,7 +24,7 @@ pub struct PromiseBegin {
 
 pub struct NotifyHandle {
     sched_state: SharedSchedState,
-    co: Box<CommonCoState>
+    co: CoBox
 }
 
 pub struct SendableNotifyHandle {
@@ -29,7 +33,7 @@ pub struct SendableNotifyHandle {
 }
 
 impl NotifyHandle {
-    pub(crate) fn new(s: SharedSchedState, co: Box<CommonCoState>) -> NotifyHandle {
+    pub(crate) fn new(s: SharedSchedState, co: CoBox) -> NotifyHandle {
         NotifyHandle {
             sched_state: s,
             co: co
@@ -40,22 +44,73 @@ impl NotifyHandle {
         self.sched_state.push_coroutine_raw(self.co);
     }
 
+    /// Notifies the coroutine once `deadline` is reached.
+    pub fn notify_at(self, deadline: Instant) {
+        self.sched_state.add_timer(deadline, self.co);
+    }
+
+    /// Notifies the coroutine once `fd` is ready for `interest`.
+    pub fn notify_when_ready(self, fd: RawFd, interest: Interest) {
+        self.sched_state.add_io_waiter(fd, interest, self.co);
+    }
+
     pub fn into_sendable(self) -> SendableNotifyHandle {
+        let sched_state = self.sched_state.get_sync();
         SendableNotifyHandle {
-            sched_state: self.sched_state.get_sync(),
-            co: SendableCoState::new(self.co)
+            co: SendableCoState::new(self.co, sched_state.clone()),
+            sched_state: sched_state
+        }
+    }
+
+    /// Detaches the coroutine from its scheduler, so that it can be resumed
+    /// by a scheduler on any thread.
+    ///
+    /// Gives the handle back if the coroutine was not started from a `Send`
+    /// closure, e.g. with `start_migratable_coroutine`.
+    ///
+    /// # Safety
+    ///
+    /// A `Send` closure only covers the values the coroutine captured. The
+    /// caller must ensure that the values the coroutine keeps on its stack
+    /// while suspended are `Send` as well, since they move to whichever
+    /// thread resumes it.
+    pub unsafe fn into_migratable(self) -> Result<MigratableCoroutine, NotifyHandle> {
+        let sched_state = self.sched_state;
+        match SendableCoState::new_migratable(self.co) {
+            Ok(co) => Ok(MigratableCoroutine {
+                co: co
+            }),
+            Err(co) => Err(NotifyHandle::new(sched_state, co))
         }
     }
 }
 
 impl SendableNotifyHandle {
-    // TODO: Is this correct?
+    /// Resumes the coroutine on the scheduler it was suspended on.
     pub fn notify(self) {
-        // Safe as long as we've made sure that the coroutine originally
-        // belongs to the sched_state
-        unsafe {
-            self.sched_state.add_coroutine(self.co);
-        }
+        // Never fails, since the coroutine is bound to `sched_state`.
+        self.sched_state.add_coroutine(self.co).unwrap_or_else(|_| unreachable!());
+    }
+
+    fn _assert_sendable(self) {
+        let _: Box<Send> = Box::new(self);
+    }
+}
+
+/// A suspended coroutine that is not bound to any scheduler.
+///
+/// Obtained with the unsafe `NotifyHandle::into_migratable`, whose caller
+/// guarantees that the values on the coroutine's stack can move to another
+/// thread.
+pub struct MigratableCoroutine {
+    co: SendableCoState
+}
+
+impl MigratableCoroutine {
+    /// Resumes the coroutine on `target`, which may run on another thread.
+    pub fn resume_on(self, target: &SyncSchedState) {
+        // Never fails, since the coroutine is not bound to any scheduler.
+        target.add_coroutine(self.co).unwrap_or_else(|_| unreachable!());
     }
 
     fn _assert_sendable(self) {
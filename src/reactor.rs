@@ -0,0 +1,405 @@
+use std::collections::{HashMap, VecDeque};
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+use std::io;
+use libc;
+use co::Yieldable;
+use mpsc_queue::CoBox;
+use promise::Promise;
+
+/// The readiness a coroutine waits for.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Interest {
+    Readable,
+    Writable
+}
+
+struct FdWaiters {
+    readers: Vec<CoBox>,
+    writers: Vec<CoBox>,
+    registered: bool
+}
+
+/// An epoll instance owned by a scheduler.
+///
+/// Fds are registered only while some coroutine is waiting on them, so
+/// closing an fd with no waiters needs no extra bookkeeping.
+pub(crate) struct Reactor {
+    epfd: RawFd,
+    wakeup_fd: RawFd,
+    fds: HashMap<RawFd, FdWaiters>,
+    events: Vec<libc::epoll_event>
+}
+
+const MAX_EVENTS: usize = 256;
+
+// Event data of the wakeup fd. Never a valid fd.
+const WAKEUP_TOKEN: u64 = u64::MAX;
+
+impl Reactor {
+    /// Creates a reactor that also wakes up when the eventfd `wakeup_fd`
+    /// is signaled. The reactor does not take ownership of `wakeup_fd`.
+    pub(crate) fn new(wakeup_fd: RawFd) -> io::Result<Reactor> {
+        let epfd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if epfd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut ev = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: WAKEUP_TOKEN
+        };
+        if unsafe { libc::epoll_ctl(epfd, libc::EPOLL_CTL_ADD, wakeup_fd, &mut ev) } != 0 {
+            let e = io::Error::last_os_error();
+            unsafe {
+                libc::close(epfd);
+            }
+            return Err(e);
+        }
+
+        Ok(Reactor {
+            epfd: epfd,
+            wakeup_fd: wakeup_fd,
+            fds: HashMap::new(),
+            events: vec![libc::epoll_event { events: 0, u64: 0 }; MAX_EVENTS]
+        })
+    }
+
+    /// Returns whether any coroutine is waiting on an fd.
+    pub(crate) fn has_waiters(&self) -> bool {
+        !self.fds.is_empty()
+    }
+
+    /// Suspends `co` until `fd` is ready for `interest`.
+    ///
+    /// Gives `co` back if `fd` cannot be watched, e.g. because it is not open.
+    pub(crate) fn add_waiter(&mut self, fd: RawFd, interest: Interest, co: CoBox) -> Result<(), CoBox> {
+        {
+            let entry = self.fds.entry(fd).or_insert_with(|| FdWaiters {
+                readers: Vec::new(),
+                writers: Vec::new(),
+                registered: false
+            });
+            match interest {
+                Interest::Readable => entry.readers.push(co),
+                Interest::Writable => entry.writers.push(co)
+            }
+        }
+
+        if self.update(fd).is_err() {
+            let entry = self.fds.get_mut(&fd).unwrap();
+            let co = match interest {
+                Interest::Readable => entry.readers.pop().unwrap(),
+                Interest::Writable => entry.writers.pop().unwrap()
+            };
+            if entry.readers.is_empty() && entry.writers.is_empty() {
+                self.fds.remove(&fd);
+            }
+            return Err(co);
+        }
+
+        Ok(())
+    }
+
+    /// Waits for at most `timeout` and moves coroutines whose fds are ready
+    /// to `ready`. Waits indefinitely if `timeout` is `None`.
+    ///
+    /// Returns the number of coroutines woken up.
+    pub(crate) fn poll(&mut self, timeout: Option<Duration>, ready: &mut VecDeque<CoBox>) -> usize {
+        let timeout_ms = match timeout {
+            Some(d) => {
+                // Round up, so that we don't wake up before a deadline and spin.
+                let mut ms = d.as_secs() * 1000 + d.subsec_millis() as u64;
+                if d.subsec_nanos() % 1000000 != 0 {
+                    ms += 1;
+                }
+                ::std::cmp::min(ms, i32::MAX as u64) as i32
+            },
+            None => -1
+        };
+
+        let n = unsafe {
+            libc::epoll_wait(self.epfd, self.events.as_mut_ptr(), self.events.len() as i32, timeout_ms)
+        };
+        if n < 0 {
+            // EINTR. Nothing is ready.
+            return 0;
+        }
+
+        let mut woken: usize = 0;
+        for i in 0..n as usize {
+            let events = self.events[i].events;
+            let data = self.events[i].u64;
+            if data == WAKEUP_TOKEN {
+                let mut value: u64 = 0;
+                unsafe {
+                    libc::read(self.wakeup_fd, &mut value as *mut u64 as *mut libc::c_void, 8);
+                }
+                continue;
+            }
+            let fd = data as RawFd;
+
+            {
+                let entry = match self.fds.get_mut(&fd) {
+                    Some(v) => v,
+                    None => continue
+                };
+                let failed = (libc::EPOLLERR | libc::EPOLLHUP) as u32;
+                if events & ((libc::EPOLLIN | libc::EPOLLRDHUP) as u32 | failed) != 0 {
+                    woken += entry.readers.len();
+                    ready.extend(entry.readers.drain(..));
+                }
+                if events & (libc::EPOLLOUT as u32 | failed) != 0 {
+                    woken += entry.writers.len();
+                    ready.extend(entry.writers.drain(..));
+                }
+            }
+
+            if self.update(fd).is_err() {
+                // Let the waiters find out about the error themselves.
+                let entry = self.fds.remove(&fd).unwrap();
+                woken += entry.readers.len() + entry.writers.len();
+                ready.extend(entry.readers);
+                ready.extend(entry.writers);
+            }
+        }
+
+        woken
+    }
+
+    /// Brings the epoll registration of `fd` in sync with its waiters.
+    fn update(&mut self, fd: RawFd) -> io::Result<()> {
+        let epfd = self.epfd;
+        let entry = self.fds.get_mut(&fd).unwrap();
+
+        let mut events: u32 = 0;
+        if !entry.readers.is_empty() {
+            events |= (libc::EPOLLIN | libc::EPOLLRDHUP) as u32;
+        }
+        if !entry.writers.is_empty() {
+            events |= libc::EPOLLOUT as u32;
+        }
+
+        if events == 0 {
+            if entry.registered {
+                // May fail if the fd has already been closed, which is fine.
+                unsafe {
+                    libc::epoll_ctl(epfd, libc::EPOLL_CTL_DEL, fd, ::std::ptr::null_mut());
+                }
+            }
+            self.fds.remove(&fd);
+            return Ok(());
+        }
+
+        let mut ev = libc::epoll_event {
+            events: events,
+            u64: fd as u64
+        };
+        unsafe {
+            if entry.registered {
+                if libc::epoll_ctl(epfd, libc::EPOLL_CTL_MOD, fd, &mut ev) == 0 {
+                    return Ok(());
+                }
+
+                // The fd was closed and its number reused, which removed the
+                // old registration.
+                if io::Error::last_os_error().raw_os_error() != Some(libc::ENOENT) {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            if libc::epoll_ctl(epfd, libc::EPOLL_CTL_ADD, fd, &mut ev) != 0 {
+                entry.registered = false;
+                return Err(io::Error::last_os_error());
+            }
+        }
+        entry.registered = true;
+        Ok(())
+    }
+}
+
+impl Drop for Reactor {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.epfd);
+        }
+    }
+}
+
+/// Returns a promise that resolves once `fd` is ready for `interest`.
+///
+/// Errors and hangups on `fd` also resolve the promise. If `fd` cannot be
+/// watched at all, the promise resolves right away so that the following
+/// I/O operation reports the error.
+pub fn readiness_promise(fd: RawFd, interest: Interest) -> Promise {
+    Promise::new(move |cb| cb.notify_when_ready(fd, interest))
+}
+
+/// Suspends the current coroutine until `fd` is readable.
+pub fn wait_readable(c: &mut Yieldable, fd: RawFd) {
+    c.yield_now(&readiness_promise(fd, Interest::Readable));
+}
+
+/// Suspends the current coroutine until `fd` is writable.
+pub fn wait_writable(c: &mut Yieldable, fd: RawFd) {
+    c.yield_now(&readiness_promise(fd, Interest::Writable));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::cell::Cell;
+    use std::thread;
+    use std::sync::mpsc;
+    use scheduler::Scheduler;
+
+    fn pipe() -> (RawFd, RawFd) {
+        let mut fds: [RawFd; 2] = [0; 2];
+        unsafe {
+            assert_eq!(libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC), 0);
+        }
+        (fds[0], fds[1])
+    }
+
+    fn close(fd: RawFd) {
+        unsafe {
+            libc::close(fd);
+        }
+    }
+
+    #[test]
+    fn coroutines_should_wait_for_readable_fds() {
+        let mut sched = Scheduler::new_default();
+        let state = sched.get_state();
+        let (rfd, wfd) = pipe();
+        let received: Rc<Cell<u8>> = Rc::new(Cell::new(0));
+        let received2 = received.clone();
+
+        state.start_coroutine(move |c| {
+            let mut buf: [u8; 1] = [0];
+            loop {
+                let n = unsafe { libc::read(rfd, buf.as_mut_ptr() as *mut libc::c_void, 1) };
+                if n == 1 {
+                    break;
+                }
+                wait_readable(c, rfd);
+            }
+            received2.set(buf[0]);
+        });
+
+        // Written from another thread while the scheduler is idle.
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            let buf: [u8; 1] = [42];
+            unsafe {
+                assert_eq!(libc::write(wfd, buf.as_ptr() as *const libc::c_void, 1), 1);
+            }
+        });
+
+        let vp = state.prepare_coroutine(|_| {});
+        sched.run_value_promise_to_end(vp).unwrap();
+        writer.join().unwrap();
+
+        assert_eq!(received.get(), 42);
+        close(rfd);
+        close(wfd);
+    }
+
+    #[test]
+    fn run_should_not_return_while_coroutines_wait_for_io() {
+        let mut sched = Scheduler::new_default();
+        let state = sched.get_state();
+        let (rfd, wfd) = pipe();
+        let received: Rc<Cell<u8>> = Rc::new(Cell::new(0));
+        let received2 = received.clone();
+
+        state.start_coroutine(move |c| {
+            let mut buf: [u8; 1] = [0];
+            loop {
+                let n = unsafe { libc::read(rfd, buf.as_mut_ptr() as *mut libc::c_void, 1) };
+                if n == 1 {
+                    break;
+                }
+                wait_readable(c, rfd);
+            }
+            received2.set(buf[0]);
+        });
+
+        // Written only after the main coroutine has finished.
+        let (tx, rx) = mpsc::channel();
+        let writer = thread::spawn(move || {
+            rx.recv().unwrap();
+            let buf: [u8; 1] = [42];
+            unsafe {
+                assert_eq!(libc::write(wfd, buf.as_ptr() as *const libc::c_void, 1), 1);
+            }
+        });
+
+        let vp = state.prepare_coroutine(move |_| {
+            tx.send(()).unwrap();
+            1
+        });
+        assert_eq!(sched.run_value_promise_to_end(vp).unwrap(), 1);
+        writer.join().unwrap();
+
+        assert_eq!(received.get(), 42);
+        drop(sched);
+        close(rfd);
+        close(wfd);
+    }
+
+    #[test]
+    fn coroutines_should_wait_for_writable_fds() {
+        let mut sched = Scheduler::new_default();
+        let state = sched.get_state();
+        let state2 = state.clone();
+        let (rfd, wfd) = pipe();
+
+        let vp = state.prepare_coroutine(move |c| {
+            let buf = [0u8; 4096];
+            let mut written: usize = 0;
+
+            // Fill the pipe up.
+            loop {
+                let n = unsafe { libc::write(wfd, buf.as_ptr() as *const libc::c_void, buf.len()) };
+                if n < 0 {
+                    break;
+                }
+                written += n as usize;
+            }
+
+            // Drain it from another coroutine.
+            state2.start_coroutine(move |c| {
+                let mut buf = [0u8; 4096];
+                let mut read: usize = 0;
+                while read < written {
+                    let n = unsafe { libc::read(rfd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+                    if n > 0 {
+                        read += n as usize;
+                    } else {
+                        wait_readable(c, rfd);
+                    }
+                }
+            });
+            wait_writable(c, wfd);
+
+            let n = unsafe { libc::write(wfd, buf.as_ptr() as *const libc::c_void, 1) };
+            assert_eq!(n, 1);
+        });
+        sched.run_value_promise_to_end(vp).unwrap();
+        close(rfd);
+        close(wfd);
+    }
+
+    #[test]
+    fn waiting_on_invalid_fds_should_resume_immediately() {
+        let mut sched = Scheduler::new_default();
+        let state = sched.get_state();
+
+        let vp = state.prepare_coroutine(|c| {
+            wait_readable(c, -1);
+            wait_writable(c, -1);
+        });
+        sched.run_value_promise_to_end(vp).unwrap();
+    }
+}
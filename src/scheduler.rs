@@ -1,14 +1,23 @@
-use std::time::Duration;
-use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use std::collections::{VecDeque, BinaryHeap};
 use std::panic::{catch_unwind, AssertUnwindSafe, resume_unwind};
 use std::any::Any;
 use std::rc::Rc;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::cell::{Cell, RefCell};
-use co::{CommonCoState, CoState, Yieldable, SendableCoState};
+use co::{CoState, Yieldable, SendableCoState};
+use shared_stack::{SharedStack, SharedStackCoState};
+use stack::Stack;
 use stack_pool::{StackPool, StackPoolConfig};
 use promise::{Promise, PromiseBegin, NotifyHandle};
 use invoke_box::OnceInvokeBox;
+use error::Error;
+use timer::Timer;
+use reactor::{Reactor, Interest};
+use mpsc_queue::{CoQueue, CoBox, Linked};
+use std::os::unix::io::RawFd;
+use libc;
 
 pub struct Scheduler {
     state: SharedSchedState
@@ -22,17 +31,27 @@ pub struct SharedSchedState {
 pub struct SharedSchedStateImpl {
     free_stacks: StackPool,
     termination_requested: bool,
-    running_cos: VecDeque<Box<CommonCoState>>,
+    running_cos: VecDeque<CoBox>,
+    timers: BinaryHeap<Timer>,
+    next_timer_seq: u64,
+    reactor: Reactor,
     sync_state: SyncSchedState
 }
 
 #[derive(Clone)]
 pub struct SyncSchedState {
-    inner: Arc<Mutex<SyncSchedStateImpl>>
+    inner: Arc<SyncSchedStateImpl>
 }
 
 pub struct SyncSchedStateImpl {
-    pending_cos: Vec<Box<CommonCoState>>
+    pending_cos: CoQueue,
+
+    // An eventfd that wakes up the scheduler when it is blocked in epoll.
+    wakeup_fd: RawFd,
+
+    // Set while the scheduler is about to block or blocked, so that
+    // notifiers only pay for the eventfd write when it is needed.
+    sleeping: AtomicBool
 }
 
 pub struct SchedulerConfig {
@@ -51,49 +70,329 @@ impl<T: 'static> ValuePromise<T> {
 }
 
 unsafe impl Send for SyncSchedStateImpl {}
+unsafe impl Sync for SyncSchedStateImpl {}
+
+impl Drop for SyncSchedStateImpl {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.wakeup_fd);
+        }
+    }
+}
 
 impl SyncSchedState {
-    // This is unsafe because we cannot check whether the coroutine originally
-    // belongs to the sched state.
-    pub(crate) unsafe fn add_coroutine(&self, co: SendableCoState) {
-        self.inner.lock().unwrap().pending_cos.push(co.unwrap());
+    fn new() -> SyncSchedState {
+        let wakeup_fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if wakeup_fd < 0 {
+            panic!("Unable to create eventfd: {}", ::std::io::Error::last_os_error());
+        }
+
+        SyncSchedState {
+            inner: Arc::new(SyncSchedStateImpl {
+                pending_cos: CoQueue::new(),
+                wakeup_fd: wakeup_fd,
+                sleeping: AtomicBool::new(false)
+            })
+        }
+    }
+
+    /// Returns whether both refer to the same scheduler.
+    pub fn same_as(&self, other: &SyncSchedState) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+
+    /// Queues a coroutine notified or migrated from another thread.
+    ///
+    /// Gives the coroutine back if it is bound to another scheduler.
+    pub(crate) fn add_coroutine(&self, co: SendableCoState) -> Result<(), SendableCoState> {
+        if !co.can_run_on(self) {
+            return Err(co);
+        }
+        self.inner.pending_cos.push(unsafe { co.unwrap() });
+
+        self.wake();
+        Ok(())
+    }
+
+    /// Wakes up the scheduler if it is blocked or about to block.
+    ///
+    /// Returns whether the scheduler was sleeping.
+    pub(crate) fn wake(&self) -> bool {
+        // Pairs with `prepare_sleep`.
+        if self.inner.sleeping.swap(false, Ordering::SeqCst) {
+            let one: u64 = 1;
+            unsafe {
+                libc::write(self.inner.wakeup_fd, &one as *const u64 as *const libc::c_void, 8);
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Pops a coroutine notified from another thread.
+    ///
+    /// Must only be called on the scheduler thread.
+    fn pop_pending(&self) -> Option<CoBox> {
+        unsafe { self.inner.pending_cos.pop() }
+    }
+
+    /// Announces that the scheduler is going to block.
+    ///
+    /// Returns false if coroutines have been notified in the meantime, in
+    /// which case the scheduler must not block.
+    fn prepare_sleep(&self) -> bool {
+        self.inner.sleeping.store(true, Ordering::SeqCst);
+        if !self.inner.pending_cos.is_empty() {
+            self.inner.sleeping.store(false, Ordering::SeqCst);
+            return false;
+        }
+        true
+    }
+
+    fn finish_sleep(&self) {
+        self.inner.sleeping.store(false, Ordering::SeqCst);
+    }
+
+    /// Returns whether the scheduler is blocked or about to block, and has
+    /// not been woken up since.
+    pub(crate) fn is_sleeping(&self) -> bool {
+        self.inner.sleeping.load(Ordering::SeqCst)
+    }
+
+    /// Returns whether coroutines notified from other threads are waiting
+    /// to be picked up.
+    pub(crate) fn has_pending(&self) -> bool {
+        !self.inner.pending_cos.is_empty()
     }
 }
 
+/// Moves the current coroutine to `target`, which may run on another thread.
+///
+/// Returns false, leaving the coroutine on its current scheduler, if the
+/// coroutine is not migratable.
+///
+/// # Safety
+///
+/// Every value alive on the coroutine's stack across the call moves to the
+/// thread of `target`. The caller must ensure that all of them are `Send`,
+/// e.g. that the coroutine holds no `Rc` or `MutexGuard` at this point.
+pub unsafe fn migrate_to(c: &mut Yieldable, target: &SyncSchedState) -> bool {
+    let target = target.clone();
+    let migrated = Arc::new(AtomicBool::new(false));
+    let migrated2 = migrated.clone();
+
+    c.yield_now(&Promise::new(move |cb| {
+        match unsafe { cb.into_migratable() } {
+            Ok(co) => {
+                migrated2.store(true, Ordering::SeqCst);
+                co.resume_on(&target);
+            },
+            Err(cb) => cb.notify()
+        }
+    }));
+    migrated.load(Ordering::SeqCst)
+}
+
 impl SharedSchedState {
     pub fn get_sync(&self) -> SyncSchedState {
         self.inner.borrow().sync_state.clone()
     }
 
-    pub(crate) fn push_coroutine_raw(&self, co: Box<CommonCoState>) {
+    pub(crate) fn push_coroutine_raw(&self, co: CoBox) {
         self.inner.borrow_mut().running_cos.push_back(co);
     }
 
+    /// Returns the number of coroutines ready to run.
+    pub(crate) fn num_runnable(&self) -> usize {
+        self.inner.borrow().running_cos.len()
+    }
+
+    pub(crate) fn has_timers(&self) -> bool {
+        !self.inner.borrow().timers.is_empty()
+    }
+
+    /// Returns whether coroutines owned by the scheduler are waiting for a
+    /// timer or I/O, or have been notified from another thread and not
+    /// picked up yet.
+    fn has_parked(&self) -> bool {
+        let this = self.inner.borrow();
+        !this.timers.is_empty() || this.reactor.has_waiters() || this.sync_state.has_pending()
+    }
+
+    /// Takes up to `max` coroutines that are ready to run and migratable,
+    /// starting from the back of the run queue.
+    pub(crate) fn take_migratable(&self, max: usize) -> Vec<SendableCoState> {
+        let mut this = self.inner.borrow_mut();
+        let mut taken: Vec<SendableCoState> = Vec::new();
+
+        let mut i = this.running_cos.len();
+        while i > 0 && taken.len() < max {
+            i -= 1;
+            if this.running_cos[i].is_migratable() {
+                let co = this.running_cos.remove(i).unwrap();
+                match SendableCoState::new_migratable(co) {
+                    Ok(co) => taken.push(co),
+                    Err(_) => unreachable!()
+                }
+            }
+        }
+        taken
+    }
+
+    pub(crate) fn add_timer(&self, deadline: Instant, co: CoBox) {
+        let mut this = self.inner.borrow_mut();
+        let seq = this.next_timer_seq;
+        this.next_timer_seq += 1;
+        this.timers.push(Timer {
+            deadline: deadline,
+            seq: seq,
+            co: co
+        });
+    }
+
+    pub(crate) fn add_io_waiter(&self, fd: RawFd, interest: Interest, co: CoBox) {
+        let mut this = self.inner.borrow_mut();
+        if let Err(co) = this.reactor.add_waiter(fd, interest, co) {
+            this.running_cos.push_back(co);
+        }
+    }
+
+    /// Moves coroutines notified from other threads to the run queue.
+    fn drain_pending(&self) {
+        let mut this = self.inner.borrow_mut();
+        while let Some(co) = this.sync_state.pop_pending() {
+            this.running_cos.push_back(co);
+        }
+    }
+
+    /// Waits for I/O events for at most `timeout`, or indefinitely if
+    /// `timeout` is `None`, and moves coroutines whose fds are ready to
+    /// the run queue.
+    fn poll_io(&self, timeout: Option<Duration>) {
+        let mut this = self.inner.borrow_mut();
+        let this = &mut *this;
+        if timeout == Some(Duration::from_millis(0)) && !this.reactor.has_waiters() {
+            return;
+        }
+        this.reactor.poll(timeout, &mut this.running_cos);
+    }
+
+    /// Moves coroutines whose deadlines have passed to the run queue.
+    ///
+    /// Returns the nearest deadline still pending.
+    fn fire_timers(&self) -> Option<Instant> {
+        let mut this = self.inner.borrow_mut();
+        if this.timers.is_empty() {
+            return None;
+        }
+
+        let now = Instant::now();
+        while this.timers.peek().map(|t| t.deadline <= now).unwrap_or(false) {
+            let timer = this.timers.pop().unwrap();
+            this.running_cos.push_back(timer.co);
+        }
+        this.timers.peek().map(|t| t.deadline)
+    }
+
     pub fn start_coroutine<F: FnOnce(&mut Yieldable) + 'static>(&self, f: F) {
+        self.start_coroutine_impl(None, f).unwrap_or_else(|e| panic!("{}", e));
+    }
+
+    /// Like `start_coroutine`, but returns an error instead of panicking
+    /// when no stack can be allocated.
+    pub fn try_start_coroutine<F: FnOnce(&mut Yieldable) + 'static>(&self, f: F) -> Result<(), Error> {
+        self.start_coroutine_impl(None, f)
+    }
+
+    /// Starts a coroutine with a stack of at least `stack_size` bytes,
+    /// drawn from the matching size class of the stack pool.
+    pub fn start_coroutine_with_stack_size<F: FnOnce(&mut Yieldable) + 'static>(&self, stack_size: usize, f: F) {
+        self.start_coroutine_impl(Some(stack_size), f).unwrap_or_else(|e| panic!("{}", e));
+    }
+
+    pub fn try_start_coroutine_with_stack_size<F: FnOnce(&mut Yieldable) + 'static>(&self, stack_size: usize, f: F) -> Result<(), Error> {
+        self.start_coroutine_impl(Some(stack_size), f)
+    }
+
+    fn start_coroutine_impl<F: FnOnce(&mut Yieldable) + 'static>(&self, stack_size: Option<usize>, f: F) -> Result<(), Error> {
+        let stack = self.get_stack(stack_size)?;
+        self.push_coroutine_raw(Box::new(Linked::new(CoState::new(
+            stack,
+            f
+        ))));
+        Ok(())
+    }
+
+    fn get_stack(&self, stack_size: Option<usize>) -> Result<Stack, Error> {
+        let this = self.inner.borrow();
+        match stack_size {
+            Some(size) => this.free_stacks.try_get_with_size(size),
+            None => this.free_stacks.try_get()
+        }
+    }
+
+    /// Starts a coroutine that can later be moved to a scheduler on another
+    /// thread while it is suspended. See `NotifyHandle::into_migratable`.
+    pub fn start_migratable_coroutine<F: FnOnce(&mut Yieldable) + Send + 'static>(&self, f: F) {
+        self.try_start_migratable_coroutine(f).unwrap_or_else(|e| panic!("{}", e));
+    }
+
+    pub fn try_start_migratable_coroutine<F: FnOnce(&mut Yieldable) + Send + 'static>(&self, f: F) -> Result<(), Error> {
         let mut this = self.inner.borrow_mut();
-        let stack = this.free_stacks.get();
-        this.running_cos.push_back(Box::new(CoState::new(
+        let stack = this.free_stacks.try_get()?;
+        this.running_cos.push_back(Box::new(Linked::new(CoState::new_migratable(
             stack,
             f
-        )));
+        ))));
+        Ok(())
+    }
+
+    /// Starts a coroutine that runs on `stack`, sharing it with other coroutines.
+    pub fn start_shared_stack_coroutine<F: FnOnce(&mut Yieldable) + 'static>(&self, stack: &SharedStack, f: F) {
+        self.push_coroutine_raw(Box::new(Linked::new(SharedStackCoState::new(
+            stack.clone(),
+            f
+        ))));
     }
 
     pub fn prepare_coroutine<R: 'static, F: FnOnce(&mut Yieldable) -> R + 'static>(&self, f: F) -> ValuePromise<Result<R, Box<Any + Send>>> {
+        self.prepare_coroutine_impl(None, f).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `prepare_coroutine`, but returns an error instead of panicking
+    /// when no stack can be allocated.
+    ///
+    /// The stack is allocated right away, so that starting the coroutine
+    /// later cannot fail.
+    pub fn try_prepare_coroutine<R: 'static, F: FnOnce(&mut Yieldable) -> R + 'static>(&self, f: F) -> Result<ValuePromise<Result<R, Box<Any + Send>>>, Error> {
+        self.prepare_coroutine_impl(None, f)
+    }
+
+    pub fn prepare_coroutine_with_stack_size<R: 'static, F: FnOnce(&mut Yieldable) -> R + 'static>(&self, stack_size: usize, f: F) -> ValuePromise<Result<R, Box<Any + Send>>> {
+        self.prepare_coroutine_impl(Some(stack_size), f).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_prepare_coroutine_with_stack_size<R: 'static, F: FnOnce(&mut Yieldable) -> R + 'static>(&self, stack_size: usize, f: F) -> Result<ValuePromise<Result<R, Box<Any + Send>>>, Error> {
+        self.prepare_coroutine_impl(Some(stack_size), f)
+    }
+
+    fn prepare_coroutine_impl<R: 'static, F: FnOnce(&mut Yieldable) -> R + 'static>(&self, stack_size: Option<usize>, f: F) -> Result<ValuePromise<Result<R, Box<Any + Send>>>, Error> {
         let value: Rc<Cell<Option<Result<R, Box<Any + Send>>>>> = Rc::new(Cell::new(None));
         let value2 = value.clone();
 
+        let stack = self.get_stack(stack_size)?;
         let this = self.clone();
-    
-        let vp: ValuePromise<Result<R, Box<Any + Send>>> = ValuePromise {
+
+        Ok(ValuePromise {
             notify: Promise::new(move |cb| {
-                this.start_coroutine(move |c| {
+                this.push_coroutine_raw(Box::new(Linked::new(CoState::new(stack, move |c| {
                     value2.set(Some(catch_unwind(AssertUnwindSafe(move || f(c)))));
                     cb.notify();
-                })
+                }))));
             }),
             value: value
-        };
-        vp
+        })
     }
 
     pub fn terminate(&self) {
@@ -103,17 +402,20 @@ impl SharedSchedState {
 
 impl Scheduler {
     pub fn new(config: SchedulerConfig) -> Scheduler {
+        let sync_state = SyncSchedState::new();
+
         Scheduler {
             state: SharedSchedState {
                 inner: Rc::new(RefCell::new(SharedSchedStateImpl {
                     free_stacks: config.stack_pool,
                     termination_requested: false,
                     running_cos: VecDeque::new(),
-                    sync_state: SyncSchedState {
-                        inner: Arc::new(Mutex::new(SyncSchedStateImpl {
-                            pending_cos: Vec::new()
-                        }))
-                    }
+                    timers: BinaryHeap::new(),
+                    next_timer_seq: 0,
+                    reactor: Reactor::new(sync_state.inner.wakeup_fd).unwrap_or_else(|e| {
+                        panic!("Unable to create epoll instance: {}", e);
+                    }),
+                    sync_state: sync_state
                 }))
             }
         }
@@ -154,6 +456,10 @@ impl Scheduler {
             Terminated
         }
 
+        self.state.drain_pending();
+        self.state.fire_timers();
+        self.state.poll_io(Some(Duration::from_millis(0)));
+
         while let Some(mut co) = {
             let mut state = self.state.inner.borrow_mut();
             state.running_cos.pop_front()
@@ -184,8 +490,9 @@ impl Scheduler {
                         begin.run(NotifyHandle::new(state, co));
                     },
                     CurrentPromiseState::Terminated => {
-                        let stack = co.take_stack().unwrap();
-                        self.state.inner.borrow_mut().free_stacks.put(stack);
+                        if let Some(stack) = co.take_stack() {
+                            self.state.inner.borrow_mut().free_stacks.put(stack);
+                        }
                     }
                 }
             })) {
@@ -203,8 +510,29 @@ impl Scheduler {
         run_count
     }
 
+    /// Blocks until a timer expires, an fd becomes ready or another thread
+    /// notifies a coroutine. Returns immediately if coroutines are runnable.
+    ///
+    /// `has_external_work` is checked after announcing the sleep, so that
+    /// work queued elsewhere before a `SyncSchedState::wake` is not missed.
+    pub(crate) fn wait_for_work<F: Fn() -> bool>(&mut self, has_external_work: F) {
+        self.state.drain_pending();
+        let next_deadline = self.state.fire_timers();
+        if !self.state.inner.borrow().running_cos.is_empty() {
+            return;
+        }
+
+        let sync_state = self.state.get_sync();
+        if sync_state.prepare_sleep() {
+            if !has_external_work() {
+                let timeout = next_deadline.map(|d| d.saturating_duration_since(Instant::now()));
+                self.state.poll_io(timeout);
+            }
+            sync_state.finish_sleep();
+        }
+    }
+
     pub fn run(&mut self) {
-        let mut sleep_micros: u64 = 0;
         let mut run_count: usize = 0;
 
         loop {
@@ -213,13 +541,12 @@ impl Scheduler {
                 run_count = 0;
             }
 
+            // Batch the work of picking up coroutines made ready
+            // elsewhere while busy.
             if run_count == 0 {
-                let mut state = self.state.inner.borrow_mut();
-                let pending = ::std::mem::replace(
-                    &mut state.sync_state.inner.lock().unwrap().pending_cos,
-                    Vec::new()
-                );
-                state.running_cos.extend(pending.into_iter());
+                self.state.drain_pending();
+                self.state.fire_timers();
+                self.state.poll_io(Some(Duration::from_millis(0)));
             }
 
             let termination_requested;
@@ -235,27 +562,17 @@ impl Scheduler {
             };
 
             let mut co = if let Some(co) = co {
-                sleep_micros = 0;
                 co
             } else {
-                if termination_requested {
+                // Coroutines waiting for a timer or I/O, or notified from
+                // another thread, are suspended on their own stacks and
+                // cannot be dropped. Wait for them to finish as well.
+                if termination_requested && !self.state.has_parked() {
                     self.state.inner.borrow_mut().termination_requested = false;
                     return;
                 }
 
-                if sleep_micros < 100 {
-                    sleep_micros += 1;
-                } else {
-                    let millis = sleep_micros / 1000;
-                    if millis > 0 {
-                        ::std::thread::sleep(Duration::from_millis(millis));
-                    }
-                    if sleep_micros < 5000 { // 5ms
-                        sleep_micros *= 2; // exponential
-                    } else if sleep_micros < 50000 { // 50ms
-                        sleep_micros += 100; // linear
-                    }
-                }
+                self.wait_for_work(|| false);
                 continue;
             };
 
@@ -293,8 +610,9 @@ impl Scheduler {
                         begin.run(NotifyHandle::new(state, co));
                     },
                     CurrentPromiseState::Terminated => {
-                        let stack = co.take_stack().unwrap();
-                        self.state.inner.borrow_mut().free_stacks.put(stack);
+                        if let Some(stack) = co.take_stack() {
+                            self.state.inner.borrow_mut().free_stacks.put(stack);
+                        }
                     }
                 }
             })) {
@@ -312,6 +630,8 @@ mod tests {
     use std::cell::Cell;
     use std::rc::Rc;
     use std::any::Any;
+    use std::sync::{Mutex, mpsc};
+    use std::thread;
 
     #[test]
     fn coroutines_should_be_scheduled() {
@@ -342,4 +662,217 @@ mod tests {
             Err(e) => resume_unwind(e)
         }
     }
+
+    #[test]
+    fn coroutines_should_get_requested_stack_sizes() {
+        use stack_pool::StackSizeClass;
+
+        let mut sched = Scheduler::new(SchedulerConfig {
+            stack_pool: StackPool::new(StackPoolConfig {
+                extra_size_classes: vec![StackSizeClass { stack_size: 1048576, max_pool_size: 4 }],
+                ..StackPoolConfig::default()
+            })
+        });
+        let state = sched.state.clone();
+
+        let vp = sched.state.prepare_coroutine_with_stack_size(1048576, move |c| {
+            // Deep enough to overflow the default stack size.
+            let arr: [u8; 262144] = ::std::hint::black_box([1; 262144]);
+            ::std::hint::black_box(&arr);
+            assert!(c.stack_begin() as usize - c.stack_end() as usize > 1048576);
+
+            let p = state.prepare_coroutine(|c| {
+                c.stack_begin() as usize - c.stack_end() as usize
+            });
+            c.yield_now(&p.notify);
+            p.take_value().unwrap().unwrap()
+        });
+        let ret = sched.run_value_promise_to_end(vp);
+
+        match ret {
+            Ok(v) => assert!(v < 1048576),
+            Err(e) => resume_unwind(e)
+        }
+        assert_eq!(sched.state.inner.borrow().free_stacks.pooled_stacks_of_size(1048576), 1);
+    }
+
+    #[test]
+    fn failed_stack_allocation_should_be_reported() {
+        use error::ErrorKind;
+
+        let sched = Scheduler::new(SchedulerConfig {
+            stack_pool: StackPool::new(StackPoolConfig {
+                default_stack_size: 1 << 60,
+                ..StackPoolConfig::default()
+            })
+        });
+
+        let e = sched.state.try_start_coroutine(|_| {}).err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::StackAllocation);
+        assert_eq!(e.errno(), ::libc::ENOMEM);
+        assert!(sched.state.inner.borrow().running_cos.is_empty());
+
+        let e = sched.state.try_prepare_coroutine(|_| 42).err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::StackAllocation);
+
+        let e = sched.state.try_start_coroutine_with_stack_size(usize::MAX, |_| {}).err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::InvalidStackSize);
+        let e = sched.state.try_prepare_coroutine_with_stack_size(usize::MAX - 1, |_| 42).err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::InvalidStackSize);
+    }
+
+    #[test]
+    fn shared_stack_coroutines_should_be_scheduled() {
+        let mut sched = Scheduler::new_default();
+        let state = sched.state.clone();
+        let shared = SharedStack::new(::stack::Stack::new(16384));
+
+        let vp = sched.state.prepare_coroutine(move |c| {
+            let done: Rc<Cell<usize>> = Rc::new(Cell::new(0));
+
+            for i in 0..32 {
+                let done = done.clone();
+                let f = move |c: &mut Yieldable| {
+                    let local: [usize; 32] = [i; 32];
+                    let p = Promise::new(|cb| cb.notify());
+                    c.yield_now(&p);
+                    c.yield_now(&Promise::new_started());
+                    assert_eq!(local, [i; 32]);
+                    done.set(done.get() + 1);
+                };
+                if i % 2 == 0 {
+                    state.start_shared_stack_coroutine(&shared, f);
+                } else {
+                    state.start_coroutine(f);
+                }
+            }
+
+            while done.get() < 32 {
+                c.yield_now(&Promise::new_started());
+            }
+        });
+        let ret = sched.run_value_promise_to_end(vp);
+
+        match ret {
+            Ok(_) => {},
+            Err(e) => resume_unwind(e)
+        }
+    }
+
+    #[test]
+    fn duplex_coroutines_should_be_scheduled() {
+        let mut sched = Scheduler::new_default();
+        let state = sched.state.clone();
+
+        let vp = sched.state.prepare_coroutine(move |c| {
+            let done: Rc<Cell<bool>> = Rc::new(Cell::new(false));
+            let done2 = done.clone();
+
+            state.push_coroutine_raw(Box::new(Linked::new(::co::DuplexCoState::new(
+                ::stack::Stack::new(16384),
+                move |c, first: Option<i32>| {
+                    assert!(first.is_none());
+                    let p = Promise::new(|cb| cb.notify());
+                    assert!(c.yield_now(&p).is_none());
+                    done2.set(true);
+                }
+            ))));
+
+            while !done.get() {
+                c.yield_now(&Promise::new_started());
+            }
+        });
+        let ret = sched.run_value_promise_to_end(vp);
+
+        match ret {
+            Ok(_) => {},
+            Err(e) => resume_unwind(e)
+        }
+    }
+
+    #[test]
+    fn idle_scheduler_should_wake_up_on_cross_thread_notify() {
+        let mut sched = Scheduler::new_default();
+
+        let vp = sched.state.prepare_coroutine(|c| {
+            let mut latencies: Vec<Duration> = Vec::new();
+            for _ in 0..3 {
+                let notified_at: Arc<Mutex<Option<Instant>>> = Arc::new(Mutex::new(None));
+                let notified_at2 = notified_at.clone();
+                let p = Promise::new(move |cb| {
+                    let cb = cb.into_sendable();
+                    ::std::thread::spawn(move || {
+                        // Long enough for the scheduler to block.
+                        ::std::thread::sleep(Duration::from_millis(100));
+                        *notified_at2.lock().unwrap() = Some(Instant::now());
+                        cb.notify();
+                    });
+                });
+                c.yield_now(&p);
+                latencies.push(notified_at.lock().unwrap().unwrap().elapsed());
+            }
+            latencies
+        });
+        let latencies = sched.run_value_promise_to_end(vp).unwrap();
+
+        // Without the eventfd the backoff would delay wakeups by up to 50ms.
+        let best = latencies.iter().min().unwrap();
+        assert!(*best < Duration::from_millis(20), "{:?}", latencies);
+    }
+
+    #[test]
+    fn coroutines_should_migrate_between_threads() {
+        let (state_tx, state_rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel();
+        let remote = thread::spawn(move || {
+            let mut sched = Scheduler::new_default();
+            let state = sched.get_state();
+            state_tx.send(state.get_sync()).unwrap();
+
+            // Keeps the remote scheduler running until the migrated
+            // coroutine is done.
+            let vp = state.prepare_coroutine(move |c| {
+                c.yield_now(&Promise::new(move |cb| {
+                    done_tx.send(cb.into_sendable()).unwrap();
+                }));
+            });
+            sched.run_value_promise_to_end(vp).unwrap();
+        });
+        let target = state_rx.recv().unwrap();
+        let done = done_rx.recv().unwrap();
+
+        let mut sched = Scheduler::new_default();
+        let (result_tx, result_rx) = mpsc::channel();
+        sched.get_state().start_migratable_coroutine(move |c| {
+            let before = thread::current().id();
+            let values: Vec<i32> = vec![1, 2, 3];
+            assert!(unsafe { migrate_to(c, &target) });
+            result_tx.send((before, thread::current().id(), values.iter().sum::<i32>())).unwrap();
+            done.notify();
+        });
+        while sched.run_once(16) > 0 {}
+
+        let (before, after, sum) = result_rx.recv().unwrap();
+        remote.join().unwrap();
+        assert_eq!(before, thread::current().id());
+        assert_ne!(after, before);
+        assert_eq!(sum, 6);
+    }
+
+    #[test]
+    fn non_migratable_coroutines_should_stay() {
+        let mut sched = Scheduler::new_default();
+        let other = Scheduler::new_default();
+        let target = other.get_state().get_sync();
+
+        let non_send: Rc<Cell<usize>> = Rc::new(Cell::new(0));
+        let non_send2 = non_send.clone();
+        let vp = sched.state.prepare_coroutine(move |c| {
+            let migrated = unsafe { migrate_to(c, &target) };
+            non_send2.set(1);
+            migrated
+        });
+        assert!(!sched.run_value_promise_to_end(vp).unwrap());
+        assert_eq!(non_send.get(), 1);
+    }
 }
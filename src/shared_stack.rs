@@ -0,0 +1,269 @@
+use std::rc::Rc;
+use std::cell::Cell;
+use std::mem;
+use std::ptr;
+use co::{CommonCoState, Yieldable, CoContext, HasCoContext};
+use stack::Stack;
+use promise::Promise;
+
+/// An execution stack shared by many coroutines.
+///
+/// Only one coroutine can run on a shared stack at a time. The used portion
+/// of a suspended coroutine's stack is kept in a heap buffer.
+#[derive(Clone)]
+pub struct SharedStack {
+    inner: Rc<SharedStackImpl>
+}
+
+struct SharedStackImpl {
+    stack: Stack,
+    in_use: Cell<bool>
+}
+
+impl SharedStack {
+    pub fn new(stack: Stack) -> SharedStack {
+        SharedStack {
+            inner: Rc::new(SharedStackImpl {
+                stack: stack,
+                in_use: Cell::new(false)
+            })
+        }
+    }
+
+    pub fn get_stack(&self) -> &Stack {
+        &self.inner.stack
+    }
+
+    fn top(&self) -> usize {
+        self.inner.stack.initial_rsp()
+    }
+}
+
+/// The state of a coroutine running on a `SharedStack`.
+///
+/// Must not be accessed by the coroutine itself.
+///
+/// While the coroutine is suspended, its stack frames live in a heap buffer
+/// and the shared stack is used by other coroutines. Pointers and references
+/// into the stack of a suspended coroutine, e.g. handed to another coroutine
+/// or kept by the resumer, are invalid until it is resumed again.
+pub struct SharedStackCoState<F: FnOnce(&mut Yieldable) + 'static> {
+    shared: SharedStack,
+
+    // Word-sized to keep values copied out of the stack aligned.
+    saved: Vec<usize>,
+
+    ctx: CoContext<F>
+}
+
+impl<F: FnOnce(&mut Yieldable) + 'static> Yieldable for SharedStackCoState<F> {
+    fn yield_now(&mut self, val: &Promise) {
+        self.ctx.yield_now(val);
+    }
+
+    fn stack_begin(&self) -> *mut u8 {
+        self.shared.top() as *mut u8
+    }
+
+    fn stack_end(&self) -> *mut u8 {
+        unsafe {
+            let mem = &mut *self.shared.inner.stack.get_mem();
+            &mut mem[0] as *mut u8
+        }
+    }
+
+    fn stack_peak_usage(&self) -> Option<usize> {
+        self.shared.inner.stack.peak_usage()
+    }
+}
+
+impl<F: FnOnce(&mut Yieldable) + 'static> HasCoContext for SharedStackCoState<F> {
+    type F = F;
+
+    fn context(&mut self) -> &mut CoContext<F> {
+        &mut self.ctx
+    }
+
+    fn call(&mut self, f: F) {
+        f(self);
+    }
+}
+
+impl<F: FnOnce(&mut Yieldable) + 'static> CommonCoState for SharedStackCoState<F> {
+    fn resume(&mut self) -> Option<&Promise> {
+        if self.ctx.is_terminated() {
+            return None;
+        }
+
+        let shared = self.shared.clone();
+        let in_use = &shared.inner.in_use;
+        if in_use.get() {
+            panic!("Attempting to resume a coroutine on a shared stack that is already in use");
+        }
+        in_use.set(true);
+
+        let top = shared.top();
+
+        unsafe {
+            if self.ctx.is_started() {
+                // Copy the saved portion back to where it was.
+                ptr::copy_nonoverlapping(
+                    self.saved.as_ptr(),
+                    self.ctx.rsp as *mut usize,
+                    self.saved.len()
+                );
+            }
+
+            let self_raw = self as *mut Self;
+            self.ctx.switch_in(self_raw, top);
+
+            // The coroutine is suspended or terminated at this point.
+            if self.ctx.is_terminated() {
+                self.saved = Vec::new();
+            } else {
+                let rsp = self.ctx.rsp;
+                let len = (top - rsp) / mem::size_of::<usize>();
+                self.saved.clear();
+                self.saved.extend_from_slice(::std::slice::from_raw_parts(rsp as *const usize, len));
+            }
+            in_use.set(false);
+
+            let (rsp, saved) = (self.ctx.rsp, self.saved.as_ptr() as usize);
+            self.ctx.take_yield_val().map(|v| {
+                let v = v as usize;
+
+                // Promises on the coroutine stack are accessed through the
+                // saved copy, since the shared stack can be reused before the
+                // promise is consumed.
+                if v >= rsp && v < top {
+                    &*((saved + (v - rsp)) as *const Promise)
+                } else {
+                    &*(v as *const Promise)
+                }
+            })
+        }
+    }
+
+    fn take_stack(&mut self) -> Option<Stack> {
+        // A shared stack is never owned by a single coroutine.
+        self.ctx.ensure_terminated();
+        None
+    }
+}
+
+impl<F: FnOnce(&mut Yieldable) + 'static> SharedStackCoState<F> {
+    pub fn new(shared: SharedStack, f: F) -> SharedStackCoState<F> {
+        let rsp = shared.top();
+
+        SharedStackCoState {
+            shared: shared,
+            saved: Vec::new(),
+            ctx: CoContext::new(rsp, f)
+        }
+    }
+
+    /// Returns the number of bytes saved off the shared stack.
+    pub fn saved_size(&self) -> usize {
+        self.saved.len() * mem::size_of::<usize>()
+    }
+}
+
+impl<F: FnOnce(&mut Yieldable) + 'static> Drop for SharedStackCoState<F> {
+    fn drop(&mut self) {
+        self.ctx.ensure_terminated();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use co::CoState;
+
+    #[test]
+    fn coroutines_should_share_a_stack() {
+        let shared = SharedStack::new(Stack::new(16384));
+        let sum: Rc<Cell<usize>> = Rc::new(Cell::new(0));
+
+        let mut cos: Vec<Box<CommonCoState>> = (0..16).map(|i| {
+            let sum = sum.clone();
+            Box::new(SharedStackCoState::new(shared.clone(), move |c| {
+                let local: [usize; 64] = [i; 64];
+                for _ in 0..3 {
+                    c.yield_now(&Promise::new_started());
+                    assert_eq!(local, [i; 64]);
+                }
+                sum.set(sum.get() + local[63]);
+            })) as Box<CommonCoState>
+        }).collect();
+
+        for _ in 0..3 {
+            for co in cos.iter_mut() {
+                assert!(co.resume().is_some());
+            }
+        }
+        for co in cos.iter_mut() {
+            assert!(co.resume().is_none());
+            assert!(co.take_stack().is_none());
+        }
+        assert_eq!(sum.get(), (0..16).sum());
+    }
+
+    #[test]
+    fn yielded_promises_should_survive_stack_reuse() {
+        let shared = SharedStack::new(Stack::new(16384));
+
+        let mut co1 = SharedStackCoState::new(shared.clone(), |c| {
+            let p = Promise::new(|_| {});
+            c.yield_now(&p);
+            assert!(p.is_started());
+        });
+        let mut co2 = SharedStackCoState::new(shared.clone(), |c| {
+            c.yield_now(&Promise::new_started());
+        });
+
+        let begin = {
+            let p = co1.resume().unwrap();
+            assert!(!p.is_started());
+            p.build_begin()
+        };
+        assert!(co2.resume().is_some());
+        drop(begin);
+
+        assert!(co1.resume().is_none());
+        assert!(co2.resume().is_none());
+    }
+
+    #[test]
+    fn nested_use_of_shared_stack_should_panic() {
+        let shared = SharedStack::new(Stack::new(65536));
+        let shared2 = shared.clone();
+
+        let mut co = SharedStackCoState::new(shared, move |_| {
+            let mut inner = SharedStackCoState::new(shared2, |_| {});
+            assert!(catch_unwind(AssertUnwindSafe(|| {
+                inner.resume();
+            })).is_err());
+
+            // `inner` never started and cannot be dropped.
+            mem::forget(inner);
+        });
+        assert!(co.resume().is_none());
+    }
+
+    #[test]
+    fn dedicated_stack_coroutines_can_resume_shared_stack_coroutines() {
+        let shared = SharedStack::new(Stack::new(16384));
+
+        let mut co = CoState::new(Stack::new(16384), move |c| {
+            let mut inner = SharedStackCoState::new(shared, |c| {
+                c.yield_now(&Promise::new_started());
+            });
+            assert!(inner.resume().is_some());
+            c.yield_now(&Promise::new_started());
+            assert!(inner.resume().is_none());
+        });
+        assert!(co.resume().is_some());
+        assert!(co.resume().is_none());
+    }
+}
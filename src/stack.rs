@@ -1,20 +1,157 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use platform;
+use overflow;
+use error::{Error, ErrorKind};
+use libc;
+
+/// The byte used to fill measured stacks.
+const STACK_FILL_PATTERN: u8 = 0xa5;
 
 pub struct Stack {
-    mem: *mut [u8]
+    mem: *mut [u8],
+    measured: bool,
+
+    // The pool this stack is checked out from.
+    tracker: Option<Arc<StackTracker>>
+}
+
+/// Keeps track of the stacks checked out from a pool, so that their memory
+/// can be reported while they are in use.
+#[derive(Default)]
+pub(crate) struct StackTracker {
+    // Start address -> length of each mapping, guard page included.
+    stacks: Mutex<HashMap<usize, usize>>
+}
+
+impl StackTracker {
+    /// Returns the number, usable size and resident size of the stacks
+    /// currently checked out.
+    pub(crate) fn usage(&self) -> Result<(usize, usize, usize), Error> {
+        // Stacks unregister themselves before being unmapped, so holding
+        // the lock keeps the mappings alive.
+        let stacks = self.stacks.lock().unwrap();
+        let reserved: usize = stacks.values().map(|len| len - *platform::PAGE_SIZE).sum();
+        let mut resident: usize = 0;
+        for (&addr, &len) in stacks.iter() {
+            resident += unsafe {
+                platform::resident_size(::std::ptr::slice_from_raw_parts_mut(addr as *mut u8, len))?
+            };
+        }
+        Ok((stacks.len(), reserved, resident))
+    }
 }
 
 unsafe impl Send for Stack {}
 
 impl Stack {
     pub fn new(stack_size: usize) -> Stack {
+        Self::try_new(stack_size).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_new(stack_size: usize) -> Result<Stack, Error> {
+        Self::allocate(stack_size, false)
+    }
+
+    /// Creates a stack without reserving swap space for it.
+    ///
+    /// Memory is committed on demand as the stack grows, so large stacks
+    /// only cost what they actually use.
+    pub fn new_noreserve(stack_size: usize) -> Stack {
+        Self::try_new_noreserve(stack_size).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_new_noreserve(stack_size: usize) -> Result<Stack, Error> {
+        Self::allocate(stack_size, true)
+    }
+
+    /// Creates a stack filled with a known pattern, so that its peak usage
+    /// can be measured with `peak_usage`.
+    ///
+    /// All pages of the stack are touched by the fill.
+    pub fn new_measured(stack_size: usize) -> Stack {
+        let mut stack = Self::new(stack_size);
+        stack.fill_pattern();
+        stack
+    }
+
+    fn allocate(stack_size: usize, noreserve: bool) -> Result<Stack, Error> {
+        if stack_size == 0 {
+            return Err(Error::new(ErrorKind::InvalidStackSize, libc::EINVAL));
+        }
+
         // Allocate one more page as the guard page
-        let mem = platform::setup_stack(stack_size + *platform::PAGE_SIZE);
+        let total_size = stack_size.checked_add(*platform::PAGE_SIZE)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidStackSize, libc::EOVERFLOW))?;
+        let mem = if noreserve {
+            platform::setup_stack_noreserve(total_size)?
+        } else {
+            platform::setup_stack(total_size)?
+        };
+        unsafe {
+            if let Err(e) = platform::setup_stack_guard_page(mem) {
+                let _ = platform::free_stack(mem);
+                return Err(e);
+            }
+        }
+        overflow::register_stack(mem);
+        Ok(Stack {
+            mem: mem,
+            measured: false,
+            tracker: None
+        })
+    }
+
+    pub(crate) fn fill_pattern(&mut self) {
+        let mem = unsafe { &mut *self.mem };
+        for b in mem[*platform::PAGE_SIZE..].iter_mut() {
+            *b = STACK_FILL_PATTERN;
+        }
+        self.measured = true;
+    }
+
+    pub fn is_measured(&self) -> bool {
+        self.measured
+    }
+
+    /// Returns the number of bytes from the top of the stack to the deepest
+    /// byte touched since the stack was created, or `None` if the stack
+    /// is not measured.
+    ///
+    /// A touched byte that happens to hold the fill pattern is not detected.
+    pub fn peak_usage(&self) -> Option<usize> {
+        if !self.measured {
+            return None;
+        }
+
+        let mem = self.usable_mem();
+        Some(match mem.iter().position(|b| *b != STACK_FILL_PATTERN) {
+            Some(i) => mem.len() - i,
+            None => 0
+        })
+    }
+
+    /// Returns the usable size of the stack, excluding the guard page.
+    pub fn size(&self) -> usize {
+        self.usable_mem().len()
+    }
+
+    /// Returns the number of bytes of the stack that are resident in memory.
+    pub fn resident_size(&self) -> Result<usize, Error> {
         unsafe {
-            platform::setup_stack_guard_page(mem);
+            platform::resident_size(self.mem)
         }
-        Stack {
-            mem: mem
+    }
+
+    /// Gives the memory of the stack back to the OS, keeping the address
+    /// range reserved. See `platform::discard_stack_pages` for `lazy`.
+    ///
+    /// Must not be called on a stack in use.
+    pub fn discard_pages(&mut self, lazy: bool) -> Result<(), Error> {
+        let mem = unsafe { &mut *self.mem };
+        let usable = &mut mem[*platform::PAGE_SIZE..] as *mut [u8];
+        unsafe {
+            platform::discard_stack_pages(usable, lazy)
         }
     }
 
@@ -26,12 +163,38 @@ impl Stack {
     pub fn get_mem(&self) -> *mut [u8] {
         self.mem
     }
+
+    /// Counts the stack as checked out from the pool owning `tracker`.
+    pub(crate) fn track(&mut self, tracker: &Arc<StackTracker>) {
+        self.untrack();
+        let mem = unsafe { &*self.mem };
+        tracker.stacks.lock().unwrap().insert(mem.as_ptr() as usize, mem.len());
+        self.tracker = Some(tracker.clone());
+    }
+
+    pub(crate) fn untrack(&mut self) {
+        if let Some(tracker) = self.tracker.take() {
+            let addr = unsafe { (*self.mem).as_ptr() as usize };
+            tracker.stacks.lock().unwrap().remove(&addr);
+        }
+    }
+
+    fn usable_mem(&self) -> &[u8] {
+        let mem = unsafe { &*self.mem };
+        &mem[*platform::PAGE_SIZE..]
+    }
 }
 
 impl Drop for Stack {
     fn drop(&mut self) {
+        self.untrack();
+        overflow::unregister_stack(self.mem);
+
+        // Leak the mapping rather than crash.
         unsafe {
-            platform::free_stack(self.mem);
+            if let Err(e) = platform::free_stack(self.mem) {
+                eprintln!("Error while dropping stack: {}", e);
+            }
         }
     }
 }
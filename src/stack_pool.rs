@@ -1,44 +1,582 @@
-use std::cell::RefCell;
-use stack::Stack;
+use std::cell::{Cell, RefCell};
+use std::sync::Arc;
+use stack::{Stack, StackTracker};
+use platform;
+use sync_stack_pool::SyncStackPool;
+use error::{Error, ErrorKind};
+use libc;
 
 pub struct StackPool {
-    stacks: RefCell<Vec<Stack>>,
+    // The first one is the default class.
+    classes: Vec<SizeClassPool>,
+    peak_usage: Cell<usize>,
+
+    // Where stacks overflowing this pool go, and where it refills from.
+    depot: Option<SyncStackPool>,
+
+    // The stacks checked out and not put back yet, if tracked.
+    outstanding: Option<Arc<StackTracker>>,
+
     config: StackPoolConfig
 }
 
+/// The free list of a single size class.
+struct SizeClassPool {
+    stack_size: usize,
+    max_pool_size: usize,
+    stacks: RefCell<Vec<Stack>>,
+
+    // The fewest idle stacks seen since the last shrink.
+    idle_low_water: Cell<usize>,
+    puts_since_shrink: Cell<usize>
+}
+
+#[derive(Clone)]
 pub struct StackPoolConfig {
     pub default_stack_size: usize,
+    pub max_pool_size: usize,
+
+    /// Size classes in addition to `default_stack_size`, for coroutines
+    /// that need bigger (or smaller) stacks.
+    pub extra_size_classes: Vec<StackSizeClass>,
+
+    /// Fill new stacks with a known pattern and measure their peak usage
+    /// when they are put back.
+    pub measure_stack_usage: bool,
+
+    /// Allocate stacks with `MAP_NORESERVE`, committing memory on demand.
+    pub noreserve: bool,
+
+    /// Keep track of the stacks checked out from the pool, so that `stats`
+    /// can report them. This costs a lock on every `get` and `put`.
+    pub track_outstanding: bool,
+
+    /// What to do with the memory of a stack when it is put back.
+    ///
+    /// Ignored for measured stacks, since discarding pages erases the fill pattern.
+    pub trim_policy: StackTrimPolicy,
+
+    /// The number of idle stacks kept in each size class by shrinking and
+    /// `StackPool::trim`.
+    pub min_idle_stacks: usize,
+
+    /// Every `shrink_interval` puts into a size class, unmap the stacks of
+    /// the class that stayed idle during the whole interval, down to
+    /// `min_idle_stacks`. Zero disables shrinking.
+    pub shrink_interval: usize
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct StackSizeClass {
+    pub stack_size: usize,
     pub max_pool_size: usize
 }
 
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum StackTrimPolicy {
+    /// Keep pages resident.
+    None,
+
+    /// Drop pages immediately with `MADV_DONTNEED`.
+    DontNeed,
+
+    /// Let the kernel reclaim pages under memory pressure with `MADV_FREE`.
+    Free
+}
+
+/// Memory statistics of the stacks of a pool.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct StackPoolStats {
+    /// Idle stacks currently in the pool.
+    pub pooled_stacks: usize,
+    pub reserved_bytes: usize,
+    pub resident_bytes: usize,
+
+    /// Stacks checked out from the pool, e.g. by running coroutines, and
+    /// not put back or dropped yet. Always zero unless `track_outstanding`
+    /// is set.
+    pub outstanding_stacks: usize,
+    pub outstanding_reserved_bytes: usize,
+    pub outstanding_resident_bytes: usize
+}
+
 impl Default for StackPoolConfig {
     fn default() -> Self {
         StackPoolConfig {
             default_stack_size: 32768,
-            max_pool_size: 4096
+            max_pool_size: 4096,
+            extra_size_classes: Vec::new(),
+            measure_stack_usage: false,
+            noreserve: false,
+            track_outstanding: false,
+            trim_policy: StackTrimPolicy::None,
+            min_idle_stacks: 0,
+            shrink_interval: 0
+        }
+    }
+}
+
+/// Releases the memory of an idle stack as `config.trim_policy` says.
+fn apply_trim_policy(s: &mut Stack, config: &StackPoolConfig) -> Result<(), Error> {
+    if !s.is_measured() {
+        match config.trim_policy {
+            StackTrimPolicy::None => {},
+            StackTrimPolicy::DontNeed => s.discard_pages(false)?,
+            StackTrimPolicy::Free => s.discard_pages(true)?
+        }
+    }
+    Ok(())
+}
+
+impl SizeClassPool {
+    fn new(class: StackSizeClass) -> SizeClassPool {
+        SizeClassPool {
+            stack_size: platform::round_up_stack_size(class.stack_size),
+            max_pool_size: class.max_pool_size,
+            stacks: RefCell::new(Vec::new()),
+            idle_low_water: Cell::new(0),
+            puts_since_shrink: Cell::new(0)
+        }
+    }
+
+    fn pop(&self, depot: Option<&SyncStackPool>) -> Option<Stack> {
+        let mut stacks = self.stacks.borrow_mut();
+        if stacks.is_empty() {
+            if let Some(depot) = depot {
+                stacks.extend(depot.take_batch(self.stack_size));
+            }
+        }
+
+        let stack = stacks.pop();
+        if stacks.len() < self.idle_low_water.get() {
+            self.idle_low_water.set(stacks.len());
+        }
+        stack
+    }
+
+    fn push(&self, mut s: Stack, config: &StackPoolConfig, depot: Option<&SyncStackPool>) {
+        {
+            let mut stacks = self.stacks.borrow_mut();
+            // A stack whose pages cannot be discarded is unmapped instead.
+            if self.max_pool_size == 0 || stacks.len() < self.max_pool_size {
+                if apply_trim_policy(&mut s, config).is_ok() {
+                    stacks.push(s);
+                }
+            } else if let Some(depot) = depot {
+                // Hand over a batch of the least recently used stacks.
+                let n = ::std::cmp::min(depot.batch_size(), stacks.len() + 1) - 1;
+                let mut batch: Vec<Stack> = stacks.drain(..n).collect();
+
+                // The depot drops what doesn't fit, starting from `s`.
+                if !depot.has_room_for(batch.len() + 1) || apply_trim_policy(&mut s, config).is_ok() {
+                    batch.push(s);
+                }
+                depot.put_batch(self.stack_size, batch);
+            }
+        }
+
+        if config.shrink_interval > 0 {
+            let puts = self.puts_since_shrink.get() + 1;
+            if puts >= config.shrink_interval {
+                self.shrink(config);
+            } else {
+                self.puts_since_shrink.set(puts);
+            }
         }
     }
+
+    /// Unmaps the stacks that have stayed idle since the last shrink.
+    fn shrink(&self, config: &StackPoolConfig) {
+        let mut stacks = self.stacks.borrow_mut();
+
+        // Stacks at the front of the list are the least recently used.
+        let surplus = self.idle_low_water.get().saturating_sub(config.min_idle_stacks);
+        stacks.drain(..surplus);
+
+        self.idle_low_water.set(stacks.len());
+        self.puts_since_shrink.set(0);
+    }
+
+    fn trim(&self, config: &StackPoolConfig) -> usize {
+        let mut stacks = self.stacks.borrow_mut();
+
+        let len = stacks.len();
+        let surplus = len.saturating_sub(config.min_idle_stacks);
+        stacks.drain(..surplus);
+
+        // Stacks whose pages cannot be discarded are unmapped instead.
+        let lazy = config.trim_policy == StackTrimPolicy::Free;
+        stacks.retain_mut(|s| s.is_measured() || s.discard_pages(lazy).is_ok());
+
+        self.idle_low_water.set(stacks.len());
+        self.puts_since_shrink.set(0);
+        len - stacks.len()
+    }
 }
 
 impl StackPool {
     pub fn new(config: StackPoolConfig) -> StackPool {
+        Self::new_with_depot(config, None)
+    }
+
+    pub(crate) fn new_with_depot(config: StackPoolConfig, depot: Option<SyncStackPool>) -> StackPool {
+        let mut classes = vec![SizeClassPool::new(StackSizeClass {
+            stack_size: config.default_stack_size,
+            max_pool_size: config.max_pool_size
+        })];
+        for class in config.extra_size_classes.iter() {
+            let class = SizeClassPool::new(*class);
+            if classes.iter().all(|c| c.stack_size != class.stack_size) {
+                classes.push(class);
+            }
+        }
+
         StackPool {
-            stacks: RefCell::new(Vec::new()),
+            classes: classes,
+            peak_usage: Cell::new(0),
+            depot: depot,
+            outstanding: if config.track_outstanding {
+                Some(Arc::new(StackTracker::default()))
+            } else {
+                None
+            },
             config: config
         }
     }
 
+    /// Gets a stack of `default_stack_size`.
     pub fn get(&self) -> Stack {
-        match self.stacks.borrow_mut().pop() {
+        self.try_get().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_get(&self) -> Result<Stack, Error> {
+        let class = &self.classes[0];
+        let stack = match class.pop(self.depot.as_ref()) {
             Some(v) => v,
-            None => Stack::new(self.config.default_stack_size)
+            None => self.new_stack(class.stack_size)?
+        };
+        Ok(self.check_out(stack))
+    }
+
+    /// Gets a stack of at least `stack_size` bytes from the smallest size
+    /// class that fits.
+    ///
+    /// If no size class is big enough, a stack of exactly `stack_size` is
+    /// allocated, and it won't be pooled when put back.
+    pub fn get_with_size(&self, stack_size: usize) -> Stack {
+        self.try_get_with_size(stack_size).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_get_with_size(&self, stack_size: usize) -> Result<Stack, Error> {
+        let stack_size = platform::checked_round_up_stack_size(stack_size)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidStackSize, libc::EOVERFLOW))?;
+        let class = self.classes.iter()
+            .filter(|c| c.stack_size >= stack_size)
+            .min_by_key(|c| c.stack_size);
+
+        let stack = match class {
+            Some(class) => match class.pop(self.depot.as_ref()) {
+                Some(v) => v,
+                None => self.new_stack(class.stack_size)?
+            },
+            None => self.new_stack(stack_size)?
+        };
+        Ok(self.check_out(stack))
+    }
+
+    fn check_out(&self, mut stack: Stack) -> Stack {
+        if let Some(ref outstanding) = self.outstanding {
+            stack.track(outstanding);
         }
+        stack
     }
 
-    pub fn put(&self, s: Stack) {
-        let mut stacks = self.stacks.borrow_mut();
-        if self.config.max_pool_size == 0 || stacks.len() < self.config.max_pool_size {
-            stacks.push(s);
+    fn new_stack(&self, stack_size: usize) -> Result<Stack, Error> {
+        let mut stack = if self.config.noreserve {
+            Stack::try_new_noreserve(stack_size)?
+        } else {
+            Stack::try_new(stack_size)?
+        };
+        if self.config.measure_stack_usage {
+            stack.fill_pattern();
+        }
+        Ok(stack)
+    }
+
+    /// Puts a stack back into the size class it belongs to.
+    pub fn put(&self, mut s: Stack) {
+        s.untrack();
+
+        if let Some(usage) = s.peak_usage() {
+            if usage > self.peak_usage.get() {
+                self.peak_usage.set(usage);
+            }
+        }
+
+        if let Some(class) = self.classes.iter().find(|c| c.stack_size == s.size()) {
+            class.push(s, &self.config, self.depot.as_ref());
+        }
+    }
+
+    /// Unmaps idle stacks down to `min_idle_stacks` in each size class,
+    /// and discards the pages of the remaining ones (except measured stacks).
+    ///
+    /// Returns the number of stacks unmapped.
+    pub fn trim(&self) -> usize {
+        self.classes.iter().map(|c| c.trim(&self.config)).sum()
+    }
+
+    /// Returns the peak usage of all measured stacks put back into the pool
+    /// so far, including the ones that have been dropped since.
+    pub fn peak_stack_usage(&self) -> usize {
+        self.peak_usage.get()
+    }
+
+    /// Returns reserved versus resident memory of the stacks in the pool,
+    /// and of the stacks checked out from it.
+    pub fn stats(&self) -> Result<StackPoolStats, Error> {
+        let mut stats = StackPoolStats::default();
+        for class in self.classes.iter() {
+            let stacks = class.stacks.borrow();
+            stats.pooled_stacks += stacks.len();
+            for s in stacks.iter() {
+                stats.reserved_bytes += s.size();
+                stats.resident_bytes += s.resident_size()?;
+            }
+        }
+
+        if let Some(ref outstanding) = self.outstanding {
+            let (n, reserved, resident) = outstanding.usage()?;
+            stats.outstanding_stacks = n;
+            stats.outstanding_reserved_bytes = reserved;
+            stats.outstanding_resident_bytes = resident;
+        }
+        Ok(stats)
+    }
+
+    /// Returns the number of stacks currently in the size class of `stack_size`.
+    pub fn pooled_stacks_of_size(&self, stack_size: usize) -> usize {
+        let stack_size = platform::round_up_stack_size(stack_size);
+        match self.classes.iter().find(|c| c.stack_size == stack_size) {
+            Some(c) => c.stacks.borrow().len(),
+            None => 0
+        }
+    }
+
+    /// Returns the peak usage of each measured stack currently in the pool.
+    pub fn stack_peak_usages(&self) -> Vec<usize> {
+        self.classes.iter()
+            .flat_map(|c| c.stacks.borrow().iter().filter_map(|s| s.peak_usage()).collect::<Vec<usize>>())
+            .collect()
+    }
+}
+
+impl Drop for StackPool {
+    fn drop(&mut self) {
+        if let Some(ref depot) = self.depot {
+            for class in self.classes.iter() {
+                let stacks = ::std::mem::take(&mut *class.stacks.borrow_mut());
+                depot.put_batch(class.stack_size, stacks);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use co::{CoState, CommonCoState};
+    use promise::Promise;
+
+    #[test]
+    fn peak_stack_usage_should_be_collected() {
+        let pool = StackPool::new(StackPoolConfig {
+            measure_stack_usage: true,
+            ..StackPoolConfig::default()
+        });
+        assert_eq!(pool.peak_stack_usage(), 0);
+
+        let mut co = CoState::new(pool.get(), |_| {
+            let arr: [u8; 8192] = ::std::hint::black_box([1; 8192]);
+            ::std::hint::black_box(&arr);
+        });
+        assert!(co.resume().is_none());
+        pool.put(co.take_stack().unwrap());
+
+        let peak = pool.peak_stack_usage();
+        assert!(peak >= 8192 && peak <= pool.config.default_stack_size);
+        assert_eq!(pool.stack_peak_usages(), vec![peak]);
+    }
+
+    #[test]
+    fn noreserve_stacks_should_report_resident_memory() {
+        const STACK_SIZE: usize = 1048576;
+
+        let pool = StackPool::new(StackPoolConfig {
+            default_stack_size: STACK_SIZE,
+            noreserve: true,
+            ..StackPoolConfig::default()
+        });
+
+        for _ in 0..4 {
+            let mut co = CoState::new(pool.get(), |_| {});
+            assert!(co.resume().is_none());
+            pool.put(co.take_stack().unwrap());
+        }
+
+        let stats = pool.stats().unwrap();
+        assert_eq!(stats.pooled_stacks, 1);
+        assert_eq!(stats.reserved_bytes, STACK_SIZE);
+        assert!(stats.resident_bytes > 0 && stats.resident_bytes < STACK_SIZE / 4);
+    }
+
+    fn run_on(pool: &StackPool, stack: Stack) {
+        let mut co = CoState::new(stack, |_| {
+            let arr: [u8; 4096] = ::std::hint::black_box([1; 4096]);
+            ::std::hint::black_box(&arr);
+        });
+        assert!(co.resume().is_none());
+        pool.put(co.take_stack().unwrap());
+    }
+
+    #[test]
+    fn dontneed_policy_should_release_memory_on_put() {
+        let pool = StackPool::new(StackPoolConfig {
+            trim_policy: StackTrimPolicy::DontNeed,
+            ..StackPoolConfig::default()
+        });
+        run_on(&pool, pool.get());
+        assert_eq!(pool.stats().unwrap().resident_bytes, 0);
+    }
+
+    #[test]
+    fn idle_stacks_should_be_shrunk() {
+        let pool = StackPool::new(StackPoolConfig {
+            min_idle_stacks: 2,
+            shrink_interval: 4,
+            ..StackPoolConfig::default()
+        });
+
+        // Spike: 8 stacks in use at once.
+        let stacks: Vec<Stack> = (0..8).map(|_| pool.get()).collect();
+        for s in stacks {
+            pool.put(s);
+        }
+
+        // Only one stack in use at a time from now on.
+        for _ in 0..8 {
+            run_on(&pool, pool.get());
+        }
+
+        // The working stack plus `min_idle_stacks` spares.
+        assert_eq!(pool.stats().unwrap().pooled_stacks, 3);
+    }
+
+    #[test]
+    fn stacks_should_be_drawn_from_size_classes() {
+        let pool = StackPool::new(StackPoolConfig {
+            default_stack_size: 16384,
+            extra_size_classes: vec![
+                StackSizeClass { stack_size: 262144, max_pool_size: 1 },
+                StackSizeClass { stack_size: 65536, max_pool_size: 16 }
+            ],
+            ..StackPoolConfig::default()
+        });
+
+        assert_eq!(pool.get().size(), 16384);
+        assert_eq!(pool.get_with_size(100).size(), 16384);
+        assert_eq!(pool.get_with_size(20000).size(), 65536);
+        assert_eq!(pool.get_with_size(65536).size(), 65536);
+        assert_eq!(pool.get_with_size(100000).size(), 262144);
+        assert_eq!(pool.get_with_size(1048576).size(), 1048576);
+
+        let big: Vec<Stack> = (0..2).map(|_| pool.get_with_size(262144)).collect();
+        for s in big {
+            pool.put(s);
         }
+        pool.put(pool.get_with_size(65536));
+        pool.put(pool.get_with_size(1048576));
+
+        assert_eq!(pool.pooled_stacks_of_size(262144), 1);
+        assert_eq!(pool.pooled_stacks_of_size(65536), 1);
+        assert_eq!(pool.pooled_stacks_of_size(16384), 0);
+        assert_eq!(pool.stats().unwrap().pooled_stacks, 2);
+    }
+
+    #[test]
+    fn trim_should_release_idle_stacks() {
+        let pool = StackPool::new(StackPoolConfig {
+            min_idle_stacks: 1,
+            ..StackPoolConfig::default()
+        });
+
+        let stacks: Vec<Stack> = (0..4).map(|_| pool.get()).collect();
+        for s in stacks {
+            run_on(&pool, s);
+        }
+        assert_eq!(pool.stats().unwrap().pooled_stacks, 4);
+        assert!(pool.stats().unwrap().resident_bytes > 0);
+
+        assert_eq!(pool.trim(), 3);
+        assert_eq!(pool.stats().unwrap(), StackPoolStats {
+            pooled_stacks: 1,
+            reserved_bytes: pool.classes[0].stack_size,
+            resident_bytes: 0,
+            ..StackPoolStats::default()
+        });
+    }
+
+    #[test]
+    fn stacks_in_use_should_be_reported() {
+        const STACK_SIZE: usize = 1048576;
+        const N: usize = 64;
+
+        let pool = StackPool::new(StackPoolConfig {
+            default_stack_size: STACK_SIZE,
+            noreserve: true,
+            track_outstanding: true,
+            ..StackPoolConfig::default()
+        });
+
+        // Suspended coroutines holding on to their stacks.
+        let mut cos: Vec<Box<CommonCoState>> = (0..N).map(|_| {
+            let co: Box<CommonCoState> = Box::new(CoState::new(pool.get(), |c| {
+                let arr: [u8; 8192] = ::std::hint::black_box([1; 8192]);
+                ::std::hint::black_box(&arr);
+                c.yield_now(&Promise::new_started());
+            }));
+            co
+        }).collect();
+        for co in cos.iter_mut() {
+            assert!(co.resume().is_some());
+        }
+
+        let stats = pool.stats().unwrap();
+        assert_eq!(stats.pooled_stacks, 0);
+        assert_eq!(stats.outstanding_stacks, N);
+        assert_eq!(stats.outstanding_reserved_bytes, N * STACK_SIZE);
+        assert!(stats.outstanding_resident_bytes >= N * 8192);
+        assert!(stats.outstanding_resident_bytes < N * STACK_SIZE / 4);
+
+        for mut co in cos {
+            assert!(co.resume().is_none());
+            pool.put(co.take_stack().unwrap());
+        }
+        let stats = pool.stats().unwrap();
+        assert_eq!(stats.pooled_stacks, N);
+        assert_eq!(stats.outstanding_stacks, 0);
+
+        // Dropped stacks are not counted either.
+        drop(pool.get());
+        assert_eq!(pool.stats().unwrap().outstanding_stacks, 0);
+    }
+
+    #[test]
+    fn stacks_in_use_should_not_be_tracked_by_default() {
+        let pool = StackPool::new(StackPoolConfig::default());
+        let stack = pool.get();
+        assert_eq!(pool.stats().unwrap().outstanding_stacks, 0);
+        pool.put(stack);
+        assert_eq!(pool.stats().unwrap().pooled_stacks, 1);
     }
 }
@@ -0,0 +1,103 @@
+use std::cell::Cell;
+use co::Yieldable;
+use super::WaitList;
+
+/// Lets a fixed number of coroutines wait for each other.
+///
+/// The barrier can be reused once all parties have arrived.
+pub struct Barrier {
+    parties: usize,
+    arrived: Cell<usize>,
+    waiters: WaitList
+}
+
+impl Barrier {
+    /// Creates a barrier for `parties` coroutines.
+    ///
+    /// Panics if `parties` is zero.
+    pub fn new(parties: usize) -> Barrier {
+        if parties == 0 {
+            panic!("parties must be greater than zero");
+        }
+        Barrier {
+            parties: parties,
+            arrived: Cell::new(0),
+            waiters: WaitList::new()
+        }
+    }
+
+    /// Suspends the current coroutine until all parties have called `wait`.
+    ///
+    /// Returns true for exactly one of them, the last to arrive.
+    pub fn wait(&self, c: &mut Yieldable) -> bool {
+        let arrived = self.arrived.get() + 1;
+        if arrived == self.parties {
+            self.arrived.set(0);
+            self.waiters.notify_all();
+            true
+        } else {
+            self.arrived.set(arrived);
+            self.waiters.wait(c, ());
+            false
+        }
+    }
+
+    pub fn parties(&self) -> usize {
+        self.parties
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use promise::Promise;
+    use scheduler::Scheduler;
+
+    #[test]
+    fn parties_should_wait_for_each_other() {
+        const PARTIES: usize = 4;
+        const ROUNDS: usize = 3;
+
+        let mut sched = Scheduler::new_default();
+        let state = sched.get_state();
+        let barrier = Rc::new(Barrier::new(PARTIES));
+        let events: Rc<RefCell<Vec<usize>>> = Rc::new(RefCell::new(Vec::new()));
+        let leaders: Rc<Cell<usize>> = Rc::new(Cell::new(0));
+
+        for i in 0..PARTIES - 1 {
+            let barrier = barrier.clone();
+            let events = events.clone();
+            let leaders = leaders.clone();
+            state.start_coroutine(move |c| {
+                for round in 0..ROUNDS {
+                    // Arrive at different times.
+                    for _ in 0..i {
+                        c.yield_now(&Promise::new_started());
+                    }
+                    events.borrow_mut().push(round);
+                    if barrier.wait(c) {
+                        leaders.set(leaders.get() + 1);
+                    }
+                }
+            });
+        }
+
+        let vp = state.prepare_coroutine(move |c| {
+            for round in 0..ROUNDS {
+                events.borrow_mut().push(round);
+                if barrier.wait(c) {
+                    leaders.set(leaders.get() + 1);
+                }
+            }
+            (events.borrow().clone(), leaders.get())
+        });
+        let (events, leaders) = sched.run_value_promise_to_end(vp).unwrap();
+
+        // No coroutine enters a round before all have finished the last one.
+        let expected: Vec<usize> = (0..ROUNDS).flat_map(|r| vec![r; PARTIES]).collect();
+        assert_eq!(events, expected);
+        assert_eq!(leaders, ROUNDS);
+    }
+}
@@ -0,0 +1,426 @@
+//! Multi-producer single-consumer channels between coroutines.
+//!
+//! `send` suspends the calling coroutine while a bounded channel is full,
+//! and `recv` while the channel is empty. A receiver can be shared between
+//! coroutines, e.g. through an `Rc`. Waiting coroutines are woken up in
+//! FIFO order.
+//!
+//! A channel is closed by `close` on either end, when all senders are
+//! dropped, or when the receiver is dropped. Values sent before the channel
+//! was closed can still be received.
+
+use std::collections::VecDeque;
+use std::cell::RefCell;
+use std::error;
+use std::fmt;
+use std::rc::Rc;
+use co::Yieldable;
+use promise::{Promise, NotifyHandle};
+
+/// Returned by `send` when the channel is closed, carrying the value back.
+#[derive(Debug, Eq, PartialEq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum TrySendError<T> {
+    /// The channel is at capacity.
+    Full(T),
+    Closed(T)
+}
+
+/// Returned by `recv` when the channel is closed and empty.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RecvError;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TryRecvError {
+    Empty,
+
+    /// The channel is closed and empty.
+    Closed
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "sending on a closed channel")
+    }
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TrySendError::Full(_) => write!(f, "sending on a full channel"),
+            TrySendError::Closed(_) => write!(f, "sending on a closed channel")
+        }
+    }
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "receiving on a closed channel")
+    }
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TryRecvError::Empty => write!(f, "receiving on an empty channel"),
+            TryRecvError::Closed => write!(f, "receiving on a closed channel")
+        }
+    }
+}
+
+impl<T: fmt::Debug> error::Error for SendError<T> {}
+impl<T: fmt::Debug> error::Error for TrySendError<T> {}
+impl error::Error for RecvError {}
+impl error::Error for TryRecvError {}
+
+struct Chan<T> {
+    queue: VecDeque<T>,
+    capacity: Option<usize>,
+    closed: bool,
+    num_senders: usize,
+
+    // Receivers waiting for a value, in FIFO order.
+    recv_waiters: VecDeque<NotifyHandle>,
+
+    // Senders waiting for space, in FIFO order.
+    send_waiters: VecDeque<NotifyHandle>
+}
+
+impl<T> Chan<T> {
+    fn is_full(&self) -> bool {
+        match self.capacity {
+            Some(cap) => self.queue.len() >= cap,
+            None => false
+        }
+    }
+
+    fn close(&mut self) {
+        if self.closed {
+            return;
+        }
+        self.closed = true;
+
+        for h in self.recv_waiters.drain(..) {
+            h.notify();
+        }
+        for h in self.send_waiters.drain(..) {
+            h.notify();
+        }
+    }
+}
+
+pub struct Sender<T: 'static> {
+    chan: Rc<RefCell<Chan<T>>>
+}
+
+pub struct Receiver<T: 'static> {
+    chan: Rc<RefCell<Chan<T>>>
+}
+
+/// Creates a channel without a capacity limit. `send` never suspends.
+pub fn unbounded<T: 'static>() -> (Sender<T>, Receiver<T>) {
+    new_channel(None)
+}
+
+/// Creates a channel holding at most `capacity` values.
+///
+/// Panics if `capacity` is zero.
+pub fn bounded<T: 'static>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    if capacity == 0 {
+        panic!("capacity must be greater than zero");
+    }
+    new_channel(Some(capacity))
+}
+
+fn new_channel<T: 'static>(capacity: Option<usize>) -> (Sender<T>, Receiver<T>) {
+    let chan = Rc::new(RefCell::new(Chan {
+        queue: VecDeque::new(),
+        capacity: capacity,
+        closed: false,
+        num_senders: 1,
+        recv_waiters: VecDeque::new(),
+        send_waiters: VecDeque::new()
+    }));
+
+    (Sender { chan: chan.clone() }, Receiver { chan: chan })
+}
+
+impl<T: 'static> Sender<T> {
+    /// Sends `value`, suspending the current coroutine while the channel
+    /// is full.
+    pub fn send(&self, c: &mut Yieldable, value: T) -> Result<(), SendError<T>> {
+        let mut value = value;
+        loop {
+            match self.try_send(value) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Closed(v)) => return Err(SendError(v)),
+                Err(TrySendError::Full(v)) => value = v
+            }
+
+            let chan = self.chan.clone();
+            c.yield_now(&Promise::new(move |h| {
+                chan.borrow_mut().send_waiters.push_back(h);
+            }));
+        }
+    }
+
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        let mut chan = self.chan.borrow_mut();
+        if chan.closed {
+            return Err(TrySendError::Closed(value));
+        }
+        if chan.is_full() {
+            return Err(TrySendError::Full(value));
+        }
+
+        chan.queue.push_back(value);
+        if let Some(h) = chan.recv_waiters.pop_front() {
+            h.notify();
+        }
+        Ok(())
+    }
+
+    /// Closes the channel for all senders.
+    pub fn close(&self) {
+        self.chan.borrow_mut().close();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.chan.borrow().closed
+    }
+}
+
+impl<T: 'static> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.chan.borrow_mut().num_senders += 1;
+        Sender {
+            chan: self.chan.clone()
+        }
+    }
+}
+
+impl<T: 'static> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut chan = self.chan.borrow_mut();
+        chan.num_senders -= 1;
+        if chan.num_senders == 0 {
+            chan.close();
+        }
+    }
+}
+
+impl<T: 'static> Receiver<T> {
+    /// Receives a value, suspending the current coroutine while the channel
+    /// is empty.
+    ///
+    /// Fails once the channel is closed and all values have been received.
+    pub fn recv(&self, c: &mut Yieldable) -> Result<T, RecvError> {
+        loop {
+            match self.try_recv() {
+                Ok(v) => return Ok(v),
+                Err(TryRecvError::Closed) => return Err(RecvError),
+                Err(TryRecvError::Empty) => {}
+            }
+
+            let chan = self.chan.clone();
+            c.yield_now(&Promise::new(move |h| {
+                chan.borrow_mut().recv_waiters.push_back(h);
+            }));
+        }
+    }
+
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mut chan = self.chan.borrow_mut();
+        match chan.queue.pop_front() {
+            Some(v) => {
+                if let Some(h) = chan.send_waiters.pop_front() {
+                    h.notify();
+                }
+                Ok(v)
+            },
+            None => if chan.closed {
+                Err(TryRecvError::Closed)
+            } else {
+                Err(TryRecvError::Empty)
+            }
+        }
+    }
+
+    /// Closes the channel. Values already sent can still be received.
+    pub fn close(&self) {
+        self.chan.borrow_mut().close();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.chan.borrow().closed
+    }
+
+    /// Returns the number of values waiting to be received.
+    pub fn len(&self) -> usize {
+        self.chan.borrow().queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T: 'static> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.chan.borrow_mut().close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use scheduler::Scheduler;
+
+    #[test]
+    fn values_should_be_received_in_order() {
+        let mut sched = Scheduler::new_default();
+        let state = sched.get_state();
+        let (tx, rx) = unbounded::<usize>();
+
+        for i in 0..4 {
+            let tx = tx.clone();
+            state.start_coroutine(move |c| {
+                for j in 0..10 {
+                    tx.send(c, i * 10 + j).unwrap();
+                    c.yield_now(&Promise::new_started());
+                }
+            });
+        }
+        drop(tx);
+
+        let vp = state.prepare_coroutine(move |c| {
+            let mut received: Vec<usize> = Vec::new();
+            while let Ok(v) = rx.recv(c) {
+                received.push(v);
+            }
+            received
+        });
+        let received = sched.run_value_promise_to_end(vp).unwrap();
+
+        assert_eq!(received.len(), 40);
+        for i in 0..4 {
+            let from_i: Vec<usize> = received.iter().cloned().filter(|v| v / 10 == i).collect();
+            let expected: Vec<usize> = (0..10).map(|j| i * 10 + j).collect();
+            assert_eq!(from_i, expected);
+        }
+    }
+
+    #[test]
+    fn bounded_send_should_wait_for_space() {
+        let mut sched = Scheduler::new_default();
+        let state = sched.get_state();
+        let (tx, rx) = bounded::<usize>(2);
+        let sent: Rc<Cell<usize>> = Rc::new(Cell::new(0));
+
+        let sent2 = sent.clone();
+        state.start_coroutine(move |c| {
+            for i in 0..10 {
+                tx.send(c, i).unwrap();
+                sent2.set(i + 1);
+            }
+        });
+
+        let vp = state.prepare_coroutine(move |c| {
+            let mut received: Vec<usize> = Vec::new();
+            while let Ok(v) = rx.recv(c) {
+                // The sender can never be more than the capacity ahead.
+                assert!(sent.get() <= received.len() + 2 + 1);
+                received.push(v);
+                c.yield_now(&Promise::new_started());
+            }
+            received
+        });
+        let received = sched.run_value_promise_to_end(vp).unwrap();
+        assert_eq!(received, (0..10).collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn try_operations_should_not_suspend() {
+        let (tx, rx) = bounded::<u32>(1);
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        assert_eq!(tx.try_send(1), Ok(()));
+        assert_eq!(tx.try_send(2), Err(TrySendError::Full(2)));
+        assert_eq!(rx.len(), 1);
+        assert_eq!(rx.try_recv(), Ok(1));
+        assert!(rx.is_empty());
+
+        tx.try_send(3).unwrap();
+        tx.close();
+        assert!(rx.is_closed());
+        assert_eq!(tx.try_send(4), Err(TrySendError::Closed(4)));
+
+        // Values sent before closing are kept.
+        assert_eq!(rx.try_recv(), Ok(3));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Closed));
+    }
+
+    #[test]
+    fn shared_receivers_should_all_be_woken_up() {
+        let mut sched = Scheduler::new_default();
+        let state = sched.get_state();
+        let (tx, rx) = unbounded::<usize>();
+        let rx = Rc::new(rx);
+        let received: Rc<RefCell<Vec<usize>>> = Rc::new(RefCell::new(Vec::new()));
+
+        for _ in 0..2 {
+            let rx = rx.clone();
+            let received = received.clone();
+            state.start_coroutine(move |c| {
+                while let Ok(v) = rx.recv(c) {
+                    received.borrow_mut().push(v);
+                }
+            });
+        }
+        drop(rx);
+
+        let vp = state.prepare_coroutine(move |c| {
+            // Let both receivers wait on the empty channel.
+            c.yield_now(&Promise::new_started());
+
+            for i in 0..10 {
+                tx.send(c, i).unwrap();
+                if i % 3 == 0 {
+                    c.yield_now(&Promise::new_started());
+                }
+            }
+        });
+        sched.run_value_promise_to_end(vp).unwrap();
+
+        let mut received = received.borrow().clone();
+        received.sort();
+        assert_eq!(received, (0..10).collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn closing_should_wake_up_waiters() {
+        let mut sched = Scheduler::new_default();
+        let state = sched.get_state();
+
+        // A receiver waiting on a channel whose senders are dropped.
+        let (tx, rx) = unbounded::<u32>();
+        let state2 = state.clone();
+        let receiver = state.prepare_coroutine(move |c| {
+            state2.start_coroutine(move |_| drop(tx));
+            rx.recv(c)
+        });
+        assert_eq!(sched.run_value_promise_to_end(receiver).unwrap(), Err(RecvError));
+
+        // A sender waiting on a full channel whose receiver is dropped.
+        let (tx, rx) = bounded::<u32>(1);
+        tx.try_send(1).unwrap();
+        let state2 = state.clone();
+        let sender = state.prepare_coroutine(move |c| {
+            state2.start_coroutine(move |_| drop(rx));
+            tx.send(c, 2)
+        });
+        assert_eq!(sched.run_value_promise_to_end(sender).unwrap(), Err(SendError(2)));
+    }
+}
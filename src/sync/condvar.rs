@@ -0,0 +1,132 @@
+use co::Yieldable;
+use super::WaitList;
+use super::mutex::MutexGuard;
+
+/// A condition variable for coroutines, used together with `sync::Mutex`.
+///
+/// Notified coroutines are resumed in the order they started waiting.
+pub struct Condvar {
+    waiters: WaitList
+}
+
+impl Condvar {
+    pub fn new() -> Condvar {
+        Condvar {
+            waiters: WaitList::new()
+        }
+    }
+
+    /// Unlocks the mutex and suspends the current coroutine until notified,
+    /// then locks the mutex again.
+    ///
+    /// As with `std::sync::Condvar`, the condition should be checked again
+    /// after waking up.
+    pub fn wait<'a, T>(&self, c: &mut Yieldable, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.into_mutex();
+        self.waiters.wait(c, ());
+        mutex.lock(c)
+    }
+
+    /// Like `wait`, but loops until `condition` returns false.
+    pub fn wait_while<'a, T, F: FnMut(&mut T) -> bool>(&self, c: &mut Yieldable, guard: MutexGuard<'a, T>, mut condition: F) -> MutexGuard<'a, T> {
+        let mut guard = guard;
+        while condition(&mut *guard) {
+            guard = self.wait(c, guard);
+        }
+        guard
+    }
+
+    /// Wakes up the first waiting coroutine.
+    pub fn notify_one(&self) {
+        self.waiters.notify_one();
+    }
+
+    /// Wakes up all waiting coroutines.
+    pub fn notify_all(&self) {
+        self.waiters.notify_all();
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Condvar {
+        Condvar::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use promise::Promise;
+    use scheduler::Scheduler;
+    use sync::Mutex;
+
+    fn yield_a_few_times(c: &mut Yieldable) {
+        for _ in 0..3 {
+            c.yield_now(&Promise::new_started());
+        }
+    }
+
+    #[test]
+    fn waiters_should_be_notified_in_order() {
+        let mut sched = Scheduler::new_default();
+        let state = sched.get_state();
+        let pair: Rc<(Mutex<()>, Condvar)> = Rc::new((Mutex::new(()), Condvar::new()));
+        let order: Rc<RefCell<Vec<usize>>> = Rc::new(RefCell::new(Vec::new()));
+
+        for i in 0..3 {
+            let pair = pair.clone();
+            let order = order.clone();
+            state.start_coroutine(move |c| {
+                let (ref mutex, ref cond) = *pair;
+                let guard = mutex.lock(c);
+                let _guard = cond.wait(c, guard);
+                order.borrow_mut().push(i);
+            });
+        }
+
+        let vp = state.prepare_coroutine(move |c| {
+            let (_, ref cond) = *pair;
+            let mut seen: Vec<Vec<usize>> = Vec::new();
+
+            cond.notify_one();
+            yield_a_few_times(c);
+            seen.push(order.borrow().clone());
+
+            cond.notify_all();
+            yield_a_few_times(c);
+            seen.push(order.borrow().clone());
+            seen
+        });
+        assert_eq!(sched.run_value_promise_to_end(vp).unwrap(), vec![vec![0], vec![0, 1, 2]]);
+    }
+
+    #[test]
+    fn wait_should_release_the_mutex() {
+        let mut sched = Scheduler::new_default();
+        let state = sched.get_state();
+        let pair: Rc<(Mutex<bool>, Condvar)> = Rc::new((Mutex::new(false), Condvar::new()));
+
+        let pair2 = pair.clone();
+        state.start_coroutine(move |c| {
+            let (ref mutex, ref cond) = *pair2;
+            let guard = mutex.lock(c);
+            let mut ready = cond.wait_while(c, guard, |ready| !*ready);
+            *ready = false;
+        });
+
+        let vp = state.prepare_coroutine(move |c| {
+            let (ref mutex, ref cond) = *pair;
+
+            // Would never be acquired if `wait` kept the mutex locked.
+            *mutex.lock(c) = true;
+            cond.notify_one();
+            yield_a_few_times(c);
+
+            let guard = mutex.lock(c);
+            *guard
+        });
+        assert!(!sched.run_value_promise_to_end(vp).unwrap());
+    }
+}
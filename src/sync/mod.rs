@@ -0,0 +1,84 @@
+//! Synchronization between coroutines.
+//!
+//! Waiting operations take the current `Yieldable` and suspend the coroutine
+//! instead of blocking the thread. Unless noted otherwise, the types here are
+//! meant for coroutines on the same `Scheduler`.
+
+use std::collections::VecDeque;
+use std::cell::RefCell;
+use std::rc::Rc;
+use co::Yieldable;
+use promise::{Promise, NotifyHandle};
+
+pub mod channel;
+pub mod thread_channel;
+pub mod mutex;
+pub mod rwlock;
+pub mod semaphore;
+pub mod condvar;
+pub mod wait_group;
+pub mod barrier;
+
+pub use self::mutex::{Mutex, MutexGuard};
+pub use self::rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use self::semaphore::{Semaphore, SemaphorePermit};
+pub use self::condvar::Condvar;
+pub use self::wait_group::WaitGroup;
+pub use self::barrier::Barrier;
+
+/// Coroutines suspended on a synchronization primitive, in FIFO order.
+///
+/// Each waiter is tagged with a `K`, e.g. the kind of access it waits for.
+pub(crate) struct WaitList<K: 'static = ()> {
+    waiters: Rc<RefCell<VecDeque<(K, NotifyHandle)>>>
+}
+
+impl<K: 'static> WaitList<K> {
+    pub(crate) fn new() -> WaitList<K> {
+        WaitList {
+            waiters: Rc::new(RefCell::new(VecDeque::new()))
+        }
+    }
+
+    /// Suspends the current coroutine at the back of the list until it is
+    /// notified.
+    pub(crate) fn wait(&self, c: &mut Yieldable, kind: K) {
+        let waiters = self.waiters.clone();
+        c.yield_now(&Promise::new(move |h| {
+            waiters.borrow_mut().push_back((kind, h));
+        }));
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.waiters.borrow().is_empty()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.waiters.borrow().len()
+    }
+
+    /// Returns the tag of the first waiter.
+    pub(crate) fn front(&self) -> Option<K> where K: Copy {
+        self.waiters.borrow().front().map(|w| w.0)
+    }
+
+    /// Resumes the first waiter. Returns false if there is none.
+    pub(crate) fn notify_one(&self) -> bool {
+        let waiter = self.waiters.borrow_mut().pop_front();
+        match waiter {
+            Some((_, h)) => {
+                h.notify();
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Resumes all waiters, in order.
+    pub(crate) fn notify_all(&self) {
+        let waiters: Vec<(K, NotifyHandle)> = self.waiters.borrow_mut().drain(..).collect();
+        for (_, h) in waiters {
+            h.notify();
+        }
+    }
+}
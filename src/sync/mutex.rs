@@ -0,0 +1,180 @@
+use std::cell::{Cell, UnsafeCell};
+use std::ops::{Deref, DerefMut};
+use co::Yieldable;
+use super::WaitList;
+
+/// A mutual exclusion lock that suspends waiting coroutines.
+///
+/// Unlike `std::sync::Mutex`, it can be held across `yield_now`. The lock
+/// is handed over to waiters in the order they called `lock`.
+pub struct Mutex<T> {
+    locked: Cell<bool>,
+    waiters: WaitList,
+    value: UnsafeCell<T>
+}
+
+/// Unlocks the mutex when dropped.
+pub struct MutexGuard<'a, T: 'a> {
+    mutex: &'a Mutex<T>
+}
+
+impl<T> Mutex<T> {
+    pub fn new(value: T) -> Mutex<T> {
+        Mutex {
+            locked: Cell::new(false),
+            waiters: WaitList::new(),
+            value: UnsafeCell::new(value)
+        }
+    }
+
+    /// Locks the mutex, suspending the current coroutine until it is
+    /// available.
+    pub fn lock<'a>(&'a self, c: &mut Yieldable) -> MutexGuard<'a, T> {
+        if let Some(guard) = self.try_lock() {
+            return guard;
+        }
+
+        // Ownership is handed over by `unlock`.
+        self.waiters.wait(c, ());
+        MutexGuard {
+            mutex: self
+        }
+    }
+
+    /// Locks the mutex if it is available and no coroutine is waiting
+    /// for it.
+    pub fn try_lock<'a>(&'a self) -> Option<MutexGuard<'a, T>> {
+        if self.locked.get() || !self.waiters.is_empty() {
+            return None;
+        }
+        self.locked.set(true);
+        Some(MutexGuard {
+            mutex: self
+        })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.get()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.value.get() }
+    }
+
+    fn unlock(&self) {
+        // Stays locked if a waiter takes over.
+        if !self.waiters.notify_one() {
+            self.locked.set(false);
+        }
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Mutex<T> {
+        Mutex::new(T::default())
+    }
+}
+
+impl<'a, T: 'a> MutexGuard<'a, T> {
+    /// Unlocks the mutex, returning it.
+    pub(crate) fn into_mutex(self) -> &'a Mutex<T> {
+        self.mutex
+    }
+}
+
+impl<'a, T: 'a> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<'a, T: 'a> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<'a, T: 'a> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use promise::Promise;
+    use scheduler::Scheduler;
+
+    #[test]
+    fn lock_should_be_held_across_yields() {
+        let mut sched = Scheduler::new_default();
+        let state = sched.get_state();
+        let mutex: Rc<Mutex<Vec<usize>>> = Rc::new(Mutex::new(Vec::new()));
+
+        for i in 0..4 {
+            let mutex = mutex.clone();
+            state.start_coroutine(move |c| {
+                let mut v = mutex.lock(c);
+                for j in 0..3 {
+                    v.push(i * 10 + j);
+                    c.yield_now(&Promise::new_started());
+                }
+            });
+        }
+
+        let mutex2 = mutex.clone();
+        let vp = state.prepare_coroutine(move |c| {
+            mutex2.lock(c).clone()
+        });
+        let v = sched.run_value_promise_to_end(vp).unwrap();
+
+        // Each coroutine's pushes are not interleaved with others.
+        assert_eq!(v, vec![0, 1, 2, 10, 11, 12, 20, 21, 22, 30, 31, 32]);
+        assert!(!mutex.is_locked());
+    }
+
+    #[test]
+    fn waiters_should_acquire_in_fifo_order() {
+        let mut sched = Scheduler::new_default();
+        let state = sched.get_state();
+        let mutex: Rc<Mutex<()>> = Rc::new(Mutex::new(()));
+        let order: Rc<RefCell<Vec<usize>>> = Rc::new(RefCell::new(Vec::new()));
+
+        let mutex2 = mutex.clone();
+        state.start_coroutine(move |c| {
+            let guard = mutex2.lock(c);
+            c.yield_now(&Promise::new_started());
+            drop(guard);
+
+            // The lock has been handed over to the first waiter.
+            assert!(mutex2.is_locked());
+            assert!(mutex2.try_lock().is_none());
+        });
+
+        for i in 0..5 {
+            let mutex = mutex.clone();
+            let order = order.clone();
+            state.start_coroutine(move |c| {
+                let _guard = mutex.lock(c);
+                order.borrow_mut().push(i);
+            });
+        }
+
+        let mutex2 = mutex.clone();
+        let vp = state.prepare_coroutine(move |c| {
+            let _guard = mutex2.lock(c);
+            order.borrow().clone()
+        });
+        assert_eq!(sched.run_value_promise_to_end(vp).unwrap(), vec![0, 1, 2, 3, 4]);
+        assert!(!mutex.is_locked());
+    }
+}
@@ -0,0 +1,246 @@
+use std::cell::{Cell, UnsafeCell};
+use std::ops::{Deref, DerefMut};
+use co::Yieldable;
+use super::WaitList;
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Access {
+    Read,
+    Write
+}
+
+/// A reader-writer lock that suspends waiting coroutines.
+///
+/// Waiters are served in FIFO order: once a writer is waiting, new readers
+/// queue up behind it. Consecutive waiting readers are let in together.
+pub struct RwLock<T> {
+    readers: Cell<usize>,
+    writer: Cell<bool>,
+    waiters: WaitList<Access>,
+    value: UnsafeCell<T>
+}
+
+pub struct RwLockReadGuard<'a, T: 'a> {
+    lock: &'a RwLock<T>
+}
+
+pub struct RwLockWriteGuard<'a, T: 'a> {
+    lock: &'a RwLock<T>
+}
+
+impl<T> RwLock<T> {
+    pub fn new(value: T) -> RwLock<T> {
+        RwLock {
+            readers: Cell::new(0),
+            writer: Cell::new(false),
+            waiters: WaitList::new(),
+            value: UnsafeCell::new(value)
+        }
+    }
+
+    /// Locks for shared access, suspending the current coroutine while a
+    /// writer holds or waits for the lock.
+    pub fn read<'a>(&'a self, c: &mut Yieldable) -> RwLockReadGuard<'a, T> {
+        if let Some(guard) = self.try_read() {
+            return guard;
+        }
+
+        // Access is granted by `wake_waiters`.
+        self.waiters.wait(c, Access::Read);
+        RwLockReadGuard {
+            lock: self
+        }
+    }
+
+    pub fn try_read<'a>(&'a self) -> Option<RwLockReadGuard<'a, T>> {
+        if self.writer.get() || !self.waiters.is_empty() {
+            return None;
+        }
+        self.readers.set(self.readers.get() + 1);
+        Some(RwLockReadGuard {
+            lock: self
+        })
+    }
+
+    /// Locks for exclusive access, suspending the current coroutine until
+    /// all earlier holders and waiters are done.
+    pub fn write<'a>(&'a self, c: &mut Yieldable) -> RwLockWriteGuard<'a, T> {
+        if let Some(guard) = self.try_write() {
+            return guard;
+        }
+
+        self.waiters.wait(c, Access::Write);
+        RwLockWriteGuard {
+            lock: self
+        }
+    }
+
+    pub fn try_write<'a>(&'a self) -> Option<RwLockWriteGuard<'a, T>> {
+        if self.writer.get() || self.readers.get() > 0 || !self.waiters.is_empty() {
+            return None;
+        }
+        self.writer.set(true);
+        Some(RwLockWriteGuard {
+            lock: self
+        })
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.value.get() }
+    }
+
+    /// Hands the lock over to the waiters at the front, if possible.
+    fn wake_waiters(&self) {
+        if self.writer.get() {
+            return;
+        }
+
+        match self.waiters.front() {
+            Some(Access::Write) if self.readers.get() == 0 => {
+                self.writer.set(true);
+                self.waiters.notify_one();
+            },
+            Some(Access::Read) => {
+                while self.waiters.front() == Some(Access::Read) {
+                    self.readers.set(self.readers.get() + 1);
+                    self.waiters.notify_one();
+                }
+            },
+            _ => {}
+        }
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> RwLock<T> {
+        RwLock::new(T::default())
+    }
+}
+
+impl<'a, T: 'a> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T: 'a> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.readers.set(self.lock.readers.get() - 1);
+        self.lock.wake_waiters();
+    }
+}
+
+impl<'a, T: 'a> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T: 'a> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<'a, T: 'a> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.writer.set(false);
+        self.lock.wake_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use promise::Promise;
+    use scheduler::Scheduler;
+
+    #[test]
+    fn readers_should_share_the_lock() {
+        let mut sched = Scheduler::new_default();
+        let state = sched.get_state();
+        let lock: Rc<RwLock<u32>> = Rc::new(RwLock::new(42));
+        let max_readers: Rc<Cell<usize>> = Rc::new(Cell::new(0));
+
+        for _ in 0..4 {
+            let lock = lock.clone();
+            let max_readers = max_readers.clone();
+            state.start_coroutine(move |c| {
+                let v = lock.read(c);
+                assert_eq!(*v, 42);
+                c.yield_now(&Promise::new_started());
+                max_readers.set(::std::cmp::max(max_readers.get(), lock.readers.get()));
+            });
+        }
+
+        let lock2 = lock.clone();
+        let vp = state.prepare_coroutine(move |c| {
+            *lock2.write(c) += 1;
+            *lock2.read(c)
+        });
+        assert_eq!(sched.run_value_promise_to_end(vp).unwrap(), 43);
+        assert_eq!(max_readers.get(), 4);
+    }
+
+    #[test]
+    fn waiters_should_be_served_in_fifo_order() {
+        let mut sched = Scheduler::new_default();
+        let state = sched.get_state();
+        let lock: Rc<RwLock<()>> = Rc::new(RwLock::new(()));
+        let events: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+
+        // A reader holds the lock while the others queue up.
+        let lock2 = lock.clone();
+        state.start_coroutine(move |c| {
+            let _guard = lock2.read(c);
+            c.yield_now(&Promise::new_started());
+        });
+
+        let kinds = [Access::Write, Access::Read, Access::Read, Access::Write, Access::Read];
+        for (i, &kind) in kinds.iter().enumerate() {
+            let lock = lock.clone();
+            let events = events.clone();
+            state.start_coroutine(move |c| {
+                match kind {
+                    Access::Read => {
+                        let _guard = lock.read(c);
+                        events.borrow_mut().push(format!("begin r{}", i));
+                        c.yield_now(&Promise::new_started());
+                        events.borrow_mut().push(format!("end r{}", i));
+                    },
+                    Access::Write => {
+                        let _guard = lock.write(c);
+                        events.borrow_mut().push(format!("begin w{}", i));
+                        c.yield_now(&Promise::new_started());
+                        events.borrow_mut().push(format!("end w{}", i));
+                    }
+                }
+            });
+        }
+
+        let lock2 = lock.clone();
+        let vp = state.prepare_coroutine(move |c| {
+            let _guard = lock2.write(c);
+            events.borrow().clone()
+        });
+        let events = sched.run_value_promise_to_end(vp).unwrap();
+
+        assert_eq!(events, vec![
+            "begin w0", "end w0",
+            // Consecutive readers are let in together.
+            "begin r1", "begin r2", "end r1", "end r2",
+            "begin w3", "end w3",
+            "begin r4", "end r4"
+        ]);
+    }
+}
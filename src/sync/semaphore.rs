@@ -0,0 +1,155 @@
+use std::cell::Cell;
+use co::Yieldable;
+use super::WaitList;
+
+/// A counting semaphore that suspends waiting coroutines.
+///
+/// Released permits are handed over to waiters in the order they called
+/// `acquire`.
+pub struct Semaphore {
+    permits: Cell<usize>,
+    waiters: WaitList
+}
+
+/// Releases its permit when dropped.
+pub struct SemaphorePermit<'a> {
+    sem: &'a Semaphore
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            permits: Cell::new(permits),
+            waiters: WaitList::new()
+        }
+    }
+
+    /// Takes a permit, suspending the current coroutine until one is
+    /// available.
+    pub fn acquire<'a>(&'a self, c: &mut Yieldable) -> SemaphorePermit<'a> {
+        if let Some(permit) = self.try_acquire() {
+            return permit;
+        }
+
+        // The permit is handed over by `release`.
+        self.waiters.wait(c, ());
+        SemaphorePermit {
+            sem: self
+        }
+    }
+
+    /// Takes a permit if one is available and no coroutine is waiting.
+    pub fn try_acquire<'a>(&'a self) -> Option<SemaphorePermit<'a>> {
+        if self.permits.get() == 0 || !self.waiters.is_empty() {
+            return None;
+        }
+        self.permits.set(self.permits.get() - 1);
+        Some(SemaphorePermit {
+            sem: self
+        })
+    }
+
+    /// Adds a permit, waking up a waiter if any.
+    pub fn release(&self) {
+        if !self.waiters.notify_one() {
+            self.permits.set(self.permits.get() + 1);
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.permits.get()
+    }
+
+    /// Returns the number of coroutines waiting for a permit.
+    pub fn num_waiters(&self) -> usize {
+        self.waiters.len()
+    }
+}
+
+impl<'a> SemaphorePermit<'a> {
+    /// Keeps the permit taken, e.g. to give it back later with `release`.
+    pub fn forget(self) {
+        ::std::mem::forget(self);
+    }
+}
+
+impl<'a> Drop for SemaphorePermit<'a> {
+    fn drop(&mut self) {
+        self.sem.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use promise::Promise;
+    use scheduler::Scheduler;
+
+    #[test]
+    fn permits_should_limit_concurrency() {
+        let mut sched = Scheduler::new_default();
+        let state = sched.get_state();
+        let sem = Rc::new(Semaphore::new(3));
+        let running: Rc<Cell<usize>> = Rc::new(Cell::new(0));
+        let max_running: Rc<Cell<usize>> = Rc::new(Cell::new(0));
+
+        for _ in 0..10 {
+            let sem = sem.clone();
+            let running = running.clone();
+            let max_running = max_running.clone();
+            state.start_coroutine(move |c| {
+                let _permit = sem.acquire(c);
+                running.set(running.get() + 1);
+                max_running.set(::std::cmp::max(max_running.get(), running.get()));
+                for _ in 0..3 {
+                    c.yield_now(&Promise::new_started());
+                }
+                running.set(running.get() - 1);
+            });
+        }
+
+        let sem2 = sem.clone();
+        let vp = state.prepare_coroutine(move |c| {
+            let permits: Vec<SemaphorePermit> = (0..3).map(|_| sem2.acquire(c)).collect();
+            drop(permits);
+        });
+        sched.run_value_promise_to_end(vp).unwrap();
+
+        assert_eq!(max_running.get(), 3);
+        assert_eq!(sem.available_permits(), 3);
+    }
+
+    #[test]
+    fn released_permits_should_go_to_waiters_in_order() {
+        let mut sched = Scheduler::new_default();
+        let state = sched.get_state();
+        let sem = Rc::new(Semaphore::new(0));
+        let order: Rc<RefCell<Vec<usize>>> = Rc::new(RefCell::new(Vec::new()));
+
+        for i in 0..4 {
+            let sem = sem.clone();
+            let order = order.clone();
+            state.start_coroutine(move |c| {
+                sem.acquire(c).forget();
+                order.borrow_mut().push(i);
+            });
+        }
+
+        let sem2 = sem.clone();
+        let vp = state.prepare_coroutine(move |c| {
+            assert_eq!(sem2.num_waiters(), 4);
+            for _ in 0..4 {
+                sem2.release();
+
+                // Handed over, not left for newcomers.
+                assert!(sem2.try_acquire().is_none());
+                c.yield_now(&Promise::new_started());
+            }
+            order.borrow().clone()
+        });
+        assert_eq!(sched.run_value_promise_to_end(vp).unwrap(), vec![0, 1, 2, 3]);
+        assert_eq!(sem.available_permits(), 0);
+    }
+}
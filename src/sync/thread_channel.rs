@@ -0,0 +1,334 @@
+//! Unbounded channels between OS threads and coroutines.
+//!
+//! The sending end can be used from any thread, including scheduler threads,
+//! and never suspends. The receiving end can either suspend a coroutine with
+//! `recv`, or block a plain thread with `recv_blocking`. A receiver can be
+//! shared, e.g. through an `Arc`. Waiting coroutines are woken up in FIFO
+//! order.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, Condvar};
+use co::Yieldable;
+use promise::{Promise, SendableNotifyHandle};
+use super::channel::{SendError, RecvError, TryRecvError};
+
+struct Inner<T> {
+    queue: VecDeque<T>,
+    closed: bool,
+    num_senders: usize,
+
+    // Receiving coroutines waiting for a value, in FIFO order.
+    recv_waiters: VecDeque<SendableNotifyHandle>
+}
+
+struct Shared<T> {
+    inner: Mutex<Inner<T>>,
+
+    // Wakes up a receiving thread.
+    ready: Condvar
+}
+
+impl<T> Shared<T> {
+    fn close(&self) {
+        // Waiters are notified outside of the lock.
+        let waiters: Vec<SendableNotifyHandle> = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.closed = true;
+            inner.recv_waiters.drain(..).collect()
+        };
+
+        for h in waiters {
+            h.notify();
+        }
+        self.ready.notify_all();
+    }
+}
+
+pub struct Sender<T: Send + 'static> {
+    shared: Arc<Shared<T>>
+}
+
+pub struct Receiver<T: Send + 'static> {
+    shared: Arc<Shared<T>>
+}
+
+pub fn channel<T: Send + 'static>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        inner: Mutex::new(Inner {
+            queue: VecDeque::new(),
+            closed: false,
+            num_senders: 1,
+            recv_waiters: VecDeque::new()
+        }),
+        ready: Condvar::new()
+    });
+
+    (Sender { shared: shared.clone() }, Receiver { shared: shared })
+}
+
+impl<T: Send + 'static> Sender<T> {
+    /// Sends `value` and wakes up the receiver.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let waiter = {
+            let mut inner = self.shared.inner.lock().unwrap();
+            if inner.closed {
+                return Err(SendError(value));
+            }
+            inner.queue.push_back(value);
+            inner.recv_waiters.pop_front()
+        };
+
+        match waiter {
+            Some(h) => h.notify(),
+            None => self.shared.ready.notify_one()
+        }
+        Ok(())
+    }
+
+    /// Closes the channel for all senders.
+    pub fn close(&self) {
+        self.shared.close();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.inner.lock().unwrap().closed
+    }
+}
+
+impl<T: Send + 'static> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.shared.inner.lock().unwrap().num_senders += 1;
+        Sender {
+            shared: self.shared.clone()
+        }
+    }
+}
+
+impl<T: Send + 'static> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let last = {
+            let mut inner = self.shared.inner.lock().unwrap();
+            inner.num_senders -= 1;
+            inner.num_senders == 0
+        };
+        if last {
+            self.shared.close();
+        }
+    }
+}
+
+impl<T: Send + 'static> Receiver<T> {
+    /// Receives a value, suspending the current coroutine while the channel
+    /// is empty.
+    ///
+    /// Fails once the channel is closed and all values have been received.
+    pub fn recv(&self, c: &mut Yieldable) -> Result<T, RecvError> {
+        loop {
+            match self.try_recv() {
+                Ok(v) => return Ok(v),
+                Err(TryRecvError::Closed) => return Err(RecvError),
+                Err(TryRecvError::Empty) => {}
+            }
+
+            let shared = self.shared.clone();
+            c.yield_now(&Promise::new(move |h| {
+                let h = h.into_sendable();
+                let mut inner = shared.inner.lock().unwrap();
+
+                // Values may have been sent since `try_recv`.
+                if !inner.queue.is_empty() || inner.closed {
+                    drop(inner);
+                    h.notify();
+                } else {
+                    inner.recv_waiters.push_back(h);
+                }
+            }));
+        }
+    }
+
+    /// Receives a value, blocking the current thread while the channel is
+    /// empty.
+    ///
+    /// Must not be called from a coroutine, since it blocks the scheduler.
+    pub fn recv_blocking(&self) -> Result<T, RecvError> {
+        let mut inner = self.shared.inner.lock().unwrap();
+        loop {
+            if let Some(v) = inner.queue.pop_front() {
+                return Ok(v);
+            }
+            if inner.closed {
+                return Err(RecvError);
+            }
+            inner = self.shared.ready.wait(inner).unwrap();
+        }
+    }
+
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mut inner = self.shared.inner.lock().unwrap();
+        match inner.queue.pop_front() {
+            Some(v) => Ok(v),
+            None => if inner.closed {
+                Err(TryRecvError::Closed)
+            } else {
+                Err(TryRecvError::Empty)
+            }
+        }
+    }
+
+    /// Closes the channel. Values already sent can still be received.
+    pub fn close(&self) {
+        self.shared.close();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.inner.lock().unwrap().closed
+    }
+}
+
+impl<T: Send + 'static> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::cell::{Cell, RefCell};
+    use std::thread;
+    use std::time::Duration;
+    use scheduler::Scheduler;
+
+    #[test]
+    fn threads_should_feed_coroutines() {
+        let mut sched = Scheduler::new_default();
+        let (tx, rx) = channel::<usize>();
+
+        let producers: Vec<thread::JoinHandle<()>> = (0..4).map(|i| {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                for j in 0..250 {
+                    tx.send(i * 1000 + j).unwrap();
+                    if j % 50 == 0 {
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                }
+            })
+        }).collect();
+        drop(tx);
+
+        let vp = sched.get_state().prepare_coroutine(move |c| {
+            let mut received: Vec<usize> = Vec::new();
+            while let Ok(v) = rx.recv(c) {
+                received.push(v);
+            }
+            received
+        });
+        let mut received = sched.run_value_promise_to_end(vp).unwrap();
+        for t in producers {
+            t.join().unwrap();
+        }
+
+        received.sort();
+        let mut expected: Vec<usize> = (0..4).flat_map(|i| (0..250).map(move |j| i * 1000 + j)).collect();
+        expected.sort();
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn shared_receivers_on_several_threads_should_all_be_served() {
+        let (tx, rx) = channel::<usize>();
+        let rx = Arc::new(rx);
+
+        let consumers: Vec<thread::JoinHandle<Vec<usize>>> = (0..2).map(|_| {
+            let rx = rx.clone();
+            thread::spawn(move || {
+                let mut sched = Scheduler::new_default();
+                let state = sched.get_state();
+
+                let vp = sched.get_state().prepare_coroutine(move |c| {
+                    let received: Rc<RefCell<Vec<usize>>> = Rc::new(RefCell::new(Vec::new()));
+                    let done: Rc<Cell<bool>> = Rc::new(Cell::new(false));
+
+                    let rx2 = rx.clone();
+                    let received2 = received.clone();
+                    let done2 = done.clone();
+                    state.start_coroutine(move |c| {
+                        while let Ok(v) = rx2.recv(c) {
+                            received2.borrow_mut().push(v);
+                        }
+                        done2.set(true);
+                    });
+
+                    while let Ok(v) = rx.recv(c) {
+                        received.borrow_mut().push(v);
+                    }
+                    while !done.get() {
+                        c.yield_now(&Promise::new_started());
+                    }
+
+                    let received = received.borrow().clone();
+                    received
+                });
+                sched.run_value_promise_to_end(vp).unwrap()
+            })
+        }).collect();
+        drop(rx);
+
+        for i in 0..200 {
+            tx.send(i).unwrap();
+            if i % 20 == 0 {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        drop(tx);
+
+        let mut received: Vec<usize> = consumers.into_iter().flat_map(|t| t.join().unwrap()).collect();
+        received.sort();
+        assert_eq!(received, (0..200).collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn coroutines_should_feed_threads() {
+        let mut sched = Scheduler::new_default();
+        let (tx, rx) = channel::<usize>();
+
+        let consumer = thread::spawn(move || {
+            let mut received: Vec<usize> = Vec::new();
+            while let Ok(v) = rx.recv_blocking() {
+                received.push(v);
+            }
+            received
+        });
+
+        let vp = sched.get_state().prepare_coroutine(move |c| {
+            for i in 0..100 {
+                tx.send(i).unwrap();
+                c.yield_now(&Promise::new_started());
+            }
+        });
+        sched.run_value_promise_to_end(vp).unwrap();
+
+        assert_eq!(consumer.join().unwrap(), (0..100).collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn closing_from_another_thread_should_wake_up_receiver() {
+        let mut sched = Scheduler::new_default();
+        let (tx, rx) = channel::<u32>();
+
+        let closer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            tx.send(1).unwrap();
+            tx.close();
+            assert_eq!(tx.send(2), Err(SendError(2)));
+        });
+
+        let vp = sched.get_state().prepare_coroutine(move |c| {
+            (rx.recv(c), rx.recv(c))
+        });
+        assert_eq!(sched.run_value_promise_to_end(vp).unwrap(), (Ok(1), Err(RecvError)));
+        closer.join().unwrap();
+    }
+}
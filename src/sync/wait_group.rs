@@ -0,0 +1,112 @@
+use std::cell::Cell;
+use co::Yieldable;
+use super::WaitList;
+
+/// Waits for a group of coroutines to finish.
+///
+/// `add` is called for each coroutine before it is started, and `done` when
+/// it finishes. `wait` suspends the current coroutine until the count drops
+/// to zero.
+pub struct WaitGroup {
+    count: Cell<usize>,
+    waiters: WaitList
+}
+
+impl WaitGroup {
+    pub fn new() -> WaitGroup {
+        WaitGroup {
+            count: Cell::new(0),
+            waiters: WaitList::new()
+        }
+    }
+
+    pub fn add(&self, n: usize) {
+        self.count.set(self.count.get() + n);
+    }
+
+    /// Decrements the count, waking up all waiters once it reaches zero.
+    ///
+    /// Panics if the count is already zero.
+    pub fn done(&self) {
+        let count = self.count.get();
+        if count == 0 {
+            panic!("WaitGroup::done called more times than added");
+        }
+        self.count.set(count - 1);
+        if count == 1 {
+            self.waiters.notify_all();
+        }
+    }
+
+    /// Suspends the current coroutine until the count is zero.
+    pub fn wait(&self, c: &mut Yieldable) {
+        if self.count.get() > 0 {
+            self.waiters.wait(c, ());
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.get()
+    }
+}
+
+impl Default for WaitGroup {
+    fn default() -> WaitGroup {
+        WaitGroup::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::time::Duration;
+    use scheduler::Scheduler;
+    use timer;
+
+    #[test]
+    fn wait_should_return_once_all_are_done() {
+        let mut sched = Scheduler::new_default();
+        let state = sched.get_state();
+        let wg = Rc::new(WaitGroup::new());
+        let finished: Rc<Cell<usize>> = Rc::new(Cell::new(0));
+
+        for i in 0..5 {
+            wg.add(1);
+            let wg = wg.clone();
+            let finished = finished.clone();
+            state.start_coroutine(move |c| {
+                timer::sleep(c, Duration::from_millis(5 * i));
+                finished.set(finished.get() + 1);
+                wg.done();
+            });
+        }
+
+        // More than one coroutine can wait.
+        let wg2 = wg.clone();
+        let finished2 = finished.clone();
+        state.start_coroutine(move |c| {
+            wg2.wait(c);
+            assert_eq!(finished2.get(), 5);
+        });
+
+        let vp = state.prepare_coroutine(move |c| {
+            wg.wait(c);
+            let n = finished.get();
+
+            // Returns immediately at zero.
+            wg.wait(c);
+            n
+        });
+        assert_eq!(sched.run_value_promise_to_end(vp).unwrap(), 5);
+    }
+
+    #[test]
+    #[should_panic]
+    fn done_should_not_go_below_zero() {
+        let wg = WaitGroup::new();
+        wg.add(1);
+        wg.done();
+        wg.done();
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use stack::Stack;
+use stack_pool::{StackPool, StackPoolConfig};
+
+/// A stack pool shared by schedulers on many threads.
+///
+/// Each scheduler gets a thread-local `StackPool` from `local_pool` as its
+/// fast path. Stacks overflowing a local pool go to a global depot, and an
+/// empty local pool refills from the depot before allocating. A local pool
+/// hands all its stacks over to the depot when dropped.
+#[derive(Clone)]
+pub struct SyncStackPool {
+    inner: Arc<SyncStackPoolImpl>
+}
+
+struct SyncStackPoolImpl {
+    // Stack size -> idle stacks.
+    depot: Mutex<BTreeMap<usize, Vec<Stack>>>,
+    config: SyncStackPoolConfig
+}
+
+pub struct SyncStackPoolConfig {
+    /// The configuration of each thread-local pool.
+    pub local: StackPoolConfig,
+
+    /// The maximum number of stacks in the depot, across all size classes.
+    pub max_depot_size: usize,
+
+    /// The number of stacks moved between a local pool and the depot at a time.
+    pub batch_size: usize
+}
+
+impl Default for SyncStackPoolConfig {
+    fn default() -> Self {
+        SyncStackPoolConfig {
+            local: StackPoolConfig {
+                max_pool_size: 64,
+                ..StackPoolConfig::default()
+            },
+            max_depot_size: 4096,
+            batch_size: 16
+        }
+    }
+}
+
+impl SyncStackPool {
+    pub fn new(config: SyncStackPoolConfig) -> SyncStackPool {
+        if config.batch_size == 0 {
+            panic!("batch_size must be greater than zero");
+        }
+
+        SyncStackPool {
+            inner: Arc::new(SyncStackPoolImpl {
+                depot: Mutex::new(BTreeMap::new()),
+                config: config
+            })
+        }
+    }
+
+    /// Creates a thread-local pool backed by this one, to be used as
+    /// `SchedulerConfig::stack_pool`.
+    pub fn local_pool(&self) -> StackPool {
+        StackPool::new_with_depot(self.inner.config.local.clone(), Some(self.clone()))
+    }
+
+    /// Returns the number of stacks in the depot.
+    pub fn depot_size(&self) -> usize {
+        self.inner.depot.lock().unwrap().values().map(|v| v.len()).sum()
+    }
+
+    /// Unmaps all stacks in the depot.
+    ///
+    /// Returns the number of stacks unmapped.
+    pub fn trim(&self) -> usize {
+        let depot = ::std::mem::take(&mut *self.inner.depot.lock().unwrap());
+
+        // Unmap outside the lock.
+        depot.values().map(|v| v.len()).sum()
+    }
+
+    pub(crate) fn batch_size(&self) -> usize {
+        self.inner.config.batch_size
+    }
+
+    /// Returns whether `n` more stacks would currently fit in the depot.
+    pub(crate) fn has_room_for(&self, n: usize) -> bool {
+        self.depot_size() + n <= self.inner.config.max_depot_size
+    }
+
+    pub(crate) fn take_batch(&self, stack_size: usize) -> Vec<Stack> {
+        let mut depot = self.inner.depot.lock().unwrap();
+        match depot.get_mut(&stack_size) {
+            Some(stacks) => {
+                let n = ::std::cmp::min(stacks.len(), self.inner.config.batch_size);
+                let begin = stacks.len() - n;
+                stacks.split_off(begin)
+            },
+            None => Vec::new()
+        }
+    }
+
+    pub(crate) fn put_batch(&self, stack_size: usize, mut batch: Vec<Stack>) {
+        let dropped = {
+            let mut depot = self.inner.depot.lock().unwrap();
+            let total: usize = depot.values().map(|v| v.len()).sum();
+            let room = self.inner.config.max_depot_size.saturating_sub(total);
+
+            let dropped = if batch.len() > room {
+                batch.split_off(room)
+            } else {
+                Vec::new()
+            };
+            depot.entry(stack_size).or_default().extend(batch);
+            dropped
+        };
+
+        // Unmap outside the lock.
+        drop(dropped);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use stack_pool::StackSizeClass;
+    use scheduler::{Scheduler, SchedulerConfig};
+    use promise::Promise;
+
+    #[test]
+    fn stacks_should_be_shared_between_threads() {
+        let pool = SyncStackPool::new(SyncStackPoolConfig {
+            local: StackPoolConfig {
+                max_pool_size: 4,
+                ..StackPoolConfig::default()
+            },
+            max_depot_size: 32,
+            batch_size: 4
+        });
+
+        let pool2 = pool.clone();
+        thread::spawn(move || {
+            let local = pool2.local_pool();
+            let stacks: Vec<Stack> = (0..24).map(|_| local.get()).collect();
+            for s in stacks {
+                local.put(s);
+            }
+            assert_eq!(local.stats().unwrap().pooled_stacks + pool2.depot_size(), 24);
+        }).join().unwrap();
+
+        // The local pool hands its stacks over when dropped.
+        assert_eq!(pool.depot_size(), 24);
+
+        let pool2 = pool.clone();
+        thread::spawn(move || {
+            let mut sched = Scheduler::new(SchedulerConfig {
+                stack_pool: pool2.local_pool()
+            });
+            let state = sched.get_state();
+            let vp = state.prepare_coroutine(|c| {
+                c.yield_now(&Promise::new_started());
+            });
+            sched.run_value_promise_to_end(vp).unwrap();
+        }).join().unwrap();
+
+        // Reused and handed back.
+        assert_eq!(pool.depot_size(), 24);
+
+        assert_eq!(pool.trim(), 24);
+        assert_eq!(pool.depot_size(), 0);
+    }
+
+    #[test]
+    fn depot_size_should_be_bounded() {
+        let pool = SyncStackPool::new(SyncStackPoolConfig {
+            local: StackPoolConfig {
+                max_pool_size: 1,
+                extra_size_classes: vec![StackSizeClass { stack_size: 65536, max_pool_size: 1 }],
+                ..StackPoolConfig::default()
+            },
+            max_depot_size: 6,
+            batch_size: 2
+        });
+
+        let local = pool.local_pool();
+        let stacks: Vec<Stack> = (0..8).map(|_| local.get()).collect();
+        for s in stacks {
+            local.put(s);
+        }
+        let stacks: Vec<Stack> = (0..8).map(|_| local.get_with_size(65536)).collect();
+        for s in stacks {
+            local.put(s);
+        }
+        assert_eq!(pool.depot_size(), 6);
+    }
+}
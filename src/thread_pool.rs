@@ -0,0 +1,631 @@
+use std::collections::VecDeque;
+use std::cell::Cell;
+use std::error;
+use std::fmt;
+use std::mem;
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use co::{Yieldable, SendableCoState};
+use scheduler::{Scheduler, SchedulerConfig, SharedSchedState, SyncSchedState};
+use sync_stack_pool::{SyncStackPool, SyncStackPoolConfig};
+
+// The number of coroutines started or resumed between checks of the
+// local queue.
+const RUN_BATCH_SIZE: usize = 64;
+
+// The number of queued coroutines a worker starts at a time. Coroutines
+// left in the queue can still be stolen. Also the maximum number of
+// runnable coroutines a worker offers to idle workers at a time.
+const TASK_BATCH_SIZE: usize = 8;
+
+// How often dropping the pool checks whether the remaining coroutines can
+// still make progress.
+const QUIESCENCE_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+pub struct ThreadPoolConfig {
+    /// The number of worker threads.
+    pub num_threads: usize,
+
+    /// The stack pool shared by all workers.
+    pub stack_pool: SyncStackPoolConfig
+}
+
+impl Default for ThreadPoolConfig {
+    fn default() -> Self {
+        ThreadPoolConfig {
+            num_threads: thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+            stack_pool: SyncStackPoolConfig::default()
+        }
+    }
+}
+
+/// Returned by `try_spawn` when the pool has shut down.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SpawnError;
+
+impl fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "spawning on a thread pool that has shut down")
+    }
+}
+
+impl error::Error for SpawnError {}
+
+type TaskFn = Box<FnOnce(&mut Yieldable) + Send>;
+
+/// A coroutine function waiting to be started by a worker.
+struct Task {
+    f: TaskFn,
+
+    // Whether the started coroutine may move between workers.
+    migratable: bool,
+
+    _guard: OutstandingGuard
+}
+
+/// Counts a spawned coroutine as outstanding until dropped.
+struct OutstandingGuard {
+    shared: Arc<PoolShared>
+}
+
+impl Drop for OutstandingGuard {
+    fn drop(&mut self) {
+        let mut outstanding = self.shared.outstanding.lock().unwrap();
+        *outstanding -= 1;
+        if *outstanding == 0 {
+            self.shared.all_done.notify_all();
+        }
+    }
+}
+
+struct Worker {
+    tasks: Mutex<VecDeque<Task>>,
+
+    // Started coroutines ready to run, offered to idle workers.
+    runnable: Mutex<VecDeque<SendableCoState>>,
+
+    // Whether the worker had timers pending when it last went to sleep.
+    has_timers: AtomicBool,
+
+    sync_state: SyncSchedState
+}
+
+struct PoolShared {
+    workers: Vec<Worker>,
+    next_worker: AtomicUsize,
+    outstanding: Mutex<usize>,
+    all_done: Condvar,
+    shutdown: AtomicBool
+}
+
+thread_local! {
+    // (pool address, worker index) of the current thread.
+    static CURRENT_WORKER: Cell<Option<(usize, usize)>> = const { Cell::new(None) };
+}
+
+impl PoolShared {
+    fn id(&self) -> usize {
+        self as *const PoolShared as usize
+    }
+
+    fn current_worker(&self) -> Option<usize> {
+        match CURRENT_WORKER.with(|w| w.get()) {
+            Some((id, index)) if id == self.id() => Some(index),
+            _ => None
+        }
+    }
+
+    /// Queues `f`, unless the pool has shut down.
+    fn spawn(self: &Arc<Self>, f: TaskFn, migratable: bool) -> Result<(), SpawnError> {
+        // Checked under the lock taken by `shutdown`, so that no task is
+        // queued after the workers have been told to stop.
+        let mut outstanding = self.outstanding.lock().unwrap();
+        if self.shutdown.load(Ordering::SeqCst) {
+            return Err(SpawnError);
+        }
+        *outstanding += 1;
+
+        self.push(Task {
+            f: f,
+            migratable: migratable,
+            _guard: OutstandingGuard {
+                shared: self.clone()
+            }
+        });
+        Ok(())
+    }
+
+    fn push(&self, task: Task) {
+        // Keep coroutines spawned by a worker local. Idle workers steal them.
+        let index = self.current_worker().unwrap_or_else(|| {
+            self.next_worker.fetch_add(1, Ordering::Relaxed) % self.workers.len()
+        });
+
+        let backlog = {
+            let mut tasks = self.workers[index].tasks.lock().unwrap();
+            tasks.push_back(task);
+            tasks.len()
+        };
+
+        if !self.workers[index].sync_state.wake() && backlog > 1 {
+            self.wake_idle_worker(index);
+        }
+    }
+
+    /// Wakes up one sleeping worker other than `except`, so that it can
+    /// steal work.
+    fn wake_idle_worker(&self, except: usize) {
+        for (i, w) in self.workers.iter().enumerate() {
+            if i != except && w.sync_state.wake() {
+                return;
+            }
+        }
+    }
+
+    /// Takes tasks from the local queue of worker `index`, or steals up to
+    /// half of the tasks of another worker.
+    fn take_tasks(&self, index: usize) -> Vec<Task> {
+        {
+            let mut tasks = self.workers[index].tasks.lock().unwrap();
+            if !tasks.is_empty() {
+                let n = ::std::cmp::min(tasks.len(), TASK_BATCH_SIZE);
+                return tasks.drain(..n).collect();
+            }
+        }
+
+        let n_workers = self.workers.len();
+        for i in 1..n_workers {
+            let victim = &self.workers[(index + i) % n_workers];
+            let mut tasks = victim.tasks.lock().unwrap();
+            if !tasks.is_empty() {
+                let n = ::std::cmp::min(tasks.len().div_ceil(2), TASK_BATCH_SIZE);
+                let begin = tasks.len() - n;
+                return tasks.drain(begin..).collect();
+            }
+        }
+
+        Vec::new()
+    }
+
+    /// Takes back the runnable coroutines worker `index` offered, or steals
+    /// up to half of those offered by another worker.
+    fn take_runnable(&self, index: usize) -> Vec<SendableCoState> {
+        {
+            let mut runnable = self.workers[index].runnable.lock().unwrap();
+            if !runnable.is_empty() {
+                return runnable.drain(..).collect();
+            }
+        }
+
+        let n_workers = self.workers.len();
+        for i in 1..n_workers {
+            let victim = &self.workers[(index + i) % n_workers];
+            let mut runnable = victim.runnable.lock().unwrap();
+            if !runnable.is_empty() {
+                let n = runnable.len().div_ceil(2);
+                let begin = runnable.len() - n;
+                return runnable.drain(begin..).collect();
+            }
+        }
+
+        Vec::new()
+    }
+
+    /// Offers some of the migratable coroutines ready to run on worker
+    /// `index` to idle workers.
+    fn offer_runnable(&self, index: usize, state: &SharedSchedState) {
+        let n_runnable = state.num_runnable();
+        if n_runnable < 2 || !self.workers.iter().any(|w| w.sync_state.is_sleeping()) {
+            return;
+        }
+
+        let offered = {
+            let mut runnable = self.workers[index].runnable.lock().unwrap();
+            if !runnable.is_empty() {
+                return;
+            }
+            runnable.extend(state.take_migratable(::std::cmp::min(n_runnable / 2, TASK_BATCH_SIZE)));
+            !runnable.is_empty()
+        };
+        if offered {
+            self.wake_idle_worker(index);
+        }
+    }
+
+    fn has_tasks(&self) -> bool {
+        self.workers.iter().any(|w| {
+            !w.tasks.lock().unwrap().is_empty() || !w.runnable.lock().unwrap().is_empty()
+        })
+    }
+
+    /// Returns whether no coroutine can make progress without I/O or a
+    /// notification from outside the pool.
+    fn is_quiescent(&self) -> bool {
+        // `sleeping` is only cleared by a wakeup after it was set, and
+        // coroutines are queued before the wakeup.
+        self.workers.iter().all(|w| {
+            w.sync_state.is_sleeping() && !w.has_timers.load(Ordering::SeqCst)
+        }) && self.workers.iter().all(|w| !w.sync_state.has_pending()) && !self.has_tasks()
+    }
+
+    /// Stops accepting coroutines and tells the workers to stop.
+    fn shutdown(&self) {
+        {
+            let _outstanding = self.outstanding.lock().unwrap();
+            self.shutdown.store(true, Ordering::SeqCst);
+        }
+        for w in self.workers.iter() {
+            w.sync_state.wake();
+        }
+    }
+}
+
+/// A scheduler running coroutines on a pool of worker threads.
+///
+/// Each worker runs a `Scheduler` with its own run queue. Spawned coroutines
+/// are queued on a worker, and idle workers steal queued coroutines from
+/// busy ones before they start. Coroutines spawned with `spawn_migratable`
+/// can also be stolen, along with their stacks, whenever they are ready to
+/// run.
+///
+/// Coroutine functions must be `Send`, since they may be started on any
+/// worker.
+///
+/// Dropping the pool waits for spawned coroutines to complete for as long as
+/// they can make progress on their own, i.e. while any of them is ready to
+/// run or waiting for a timer. Coroutines still waiting for I/O or for a
+/// notification from outside the pool after that are leaked along with
+/// their stacks, and never resumed. Use `join` to wait for all of them.
+pub struct ThreadPoolScheduler {
+    shared: Arc<PoolShared>,
+    threads: Vec<thread::JoinHandle<()>>
+}
+
+/// A handle for spawning coroutines onto a `ThreadPoolScheduler`, e.g. from
+/// inside its coroutines.
+#[derive(Clone)]
+pub struct ThreadPoolHandle {
+    shared: Arc<PoolShared>
+}
+
+impl ThreadPoolHandle {
+    /// Panics if the pool has shut down.
+    pub fn spawn<F: FnOnce(&mut Yieldable) + Send + 'static>(&self, f: F) {
+        self.try_spawn(f).unwrap_or_else(|e| panic!("{}", e));
+    }
+
+    pub fn try_spawn<F: FnOnce(&mut Yieldable) + Send + 'static>(&self, f: F) -> Result<(), SpawnError> {
+        self.shared.spawn(Box::new(f), false)
+    }
+
+    /// Like `spawn`, but the coroutine may also be moved to another worker
+    /// every time it yields.
+    ///
+    /// # Safety
+    ///
+    /// Every value the coroutine keeps on its stack across a yield may move
+    /// to another thread. The caller must ensure that all of them are
+    /// `Send`, e.g. that the coroutine holds no `Rc` or `MutexGuard` while
+    /// it yields.
+    pub unsafe fn spawn_migratable<F: FnOnce(&mut Yieldable) + Send + 'static>(&self, f: F) {
+        self.shared.spawn(Box::new(f), true).unwrap_or_else(|e| panic!("{}", e));
+    }
+}
+
+impl ThreadPoolScheduler {
+    pub fn new(config: ThreadPoolConfig) -> ThreadPoolScheduler {
+        if config.num_threads == 0 {
+            panic!("num_threads must be greater than zero");
+        }
+
+        let stack_pool = SyncStackPool::new(config.stack_pool);
+
+        // Schedulers are created on their own threads. Collect their sync
+        // states before publishing the pool.
+        let (state_tx, state_rx) = mpsc::channel();
+        let (shared_txs, threads): (Vec<_>, Vec<_>) = (0..config.num_threads).map(|index| {
+            let state_tx = state_tx.clone();
+            let (shared_tx, shared_rx) = mpsc::channel::<Arc<PoolShared>>();
+            let local_pool = stack_pool.clone();
+
+            let t = thread::Builder::new()
+                .name(format!("liblightning-worker-{}", index))
+                .spawn(move || {
+                    let sched = Scheduler::new(SchedulerConfig {
+                        stack_pool: local_pool.local_pool()
+                    });
+                    state_tx.send((index, sched.get_state().get_sync())).unwrap();
+                    drop(state_tx);
+                    let shared = match shared_rx.recv() {
+                        Ok(v) => v,
+                        Err(_) => return
+                    };
+                    worker_main(sched, shared, index);
+                })
+                .unwrap_or_else(|e| panic!("Unable to spawn worker thread: {}", e));
+            (shared_tx, t)
+        }).unzip();
+        drop(state_tx);
+
+        let mut sync_states: Vec<Option<SyncSchedState>> = (0..config.num_threads).map(|_| None).collect();
+        for (index, state) in state_rx.iter().take(config.num_threads) {
+            sync_states[index] = Some(state);
+        }
+
+        let shared = Arc::new(PoolShared {
+            workers: sync_states.into_iter().map(|s| Worker {
+                tasks: Mutex::new(VecDeque::new()),
+                runnable: Mutex::new(VecDeque::new()),
+                has_timers: AtomicBool::new(false),
+                sync_state: s.expect("Worker thread failed to start")
+            }).collect(),
+            next_worker: AtomicUsize::new(0),
+            outstanding: Mutex::new(0),
+            all_done: Condvar::new(),
+            shutdown: AtomicBool::new(false)
+        });
+        for tx in shared_txs {
+            tx.send(shared.clone()).unwrap();
+        }
+
+        ThreadPoolScheduler {
+            shared: shared,
+            threads: threads
+        }
+    }
+
+    pub fn new_default() -> ThreadPoolScheduler {
+        Self::new(ThreadPoolConfig::default())
+    }
+
+    pub fn handle(&self) -> ThreadPoolHandle {
+        ThreadPoolHandle {
+            shared: self.shared.clone()
+        }
+    }
+
+    pub fn num_threads(&self) -> usize {
+        self.shared.workers.len()
+    }
+
+    pub fn spawn<F: FnOnce(&mut Yieldable) + Send + 'static>(&self, f: F) {
+        self.handle().spawn(f);
+    }
+
+    /// Like `spawn`, but the coroutine may also be moved to another worker
+    /// every time it yields.
+    ///
+    /// # Safety
+    ///
+    /// See `ThreadPoolHandle::spawn_migratable`.
+    pub unsafe fn spawn_migratable<F: FnOnce(&mut Yieldable) + Send + 'static>(&self, f: F) {
+        self.handle().spawn_migratable(f);
+    }
+
+    /// Waits until all spawned coroutines, including those spawned by
+    /// other coroutines, have completed.
+    ///
+    /// Blocks forever if a coroutine is never woken up.
+    pub fn wait_idle(&self) {
+        let mut outstanding = self.shared.outstanding.lock().unwrap();
+        while *outstanding > 0 {
+            outstanding = self.shared.all_done.wait(outstanding).unwrap();
+        }
+    }
+
+    /// Waits for all coroutines to complete and stops the workers.
+    ///
+    /// Blocks forever if a coroutine is never woken up.
+    pub fn join(self) {
+        self.wait_idle();
+    }
+}
+
+impl Drop for ThreadPoolScheduler {
+    fn drop(&mut self) {
+        {
+            let mut outstanding = self.shared.outstanding.lock().unwrap();
+            while *outstanding > 0 && !self.shared.is_quiescent() {
+                outstanding = self.shared.all_done.wait_timeout(outstanding, QUIESCENCE_CHECK_INTERVAL).unwrap().0;
+            }
+        }
+
+        self.shared.shutdown();
+        for t in self.threads.drain(..) {
+            let _ = t.join();
+        }
+    }
+}
+
+fn worker_main(mut sched: Scheduler, shared: Arc<PoolShared>, index: usize) {
+    CURRENT_WORKER.with(|w| w.set(Some((shared.id(), index))));
+    let state = sched.get_state();
+
+    loop {
+        for task in shared.take_tasks(index) {
+            let Task { f, migratable, _guard } = task;
+            let f = move |c: &mut Yieldable| {
+                let _guard = _guard;
+                f(c);
+            };
+            if migratable {
+                state.start_migratable_coroutine(f);
+            } else {
+                state.start_coroutine(f);
+            }
+        }
+
+        if state.num_runnable() == 0 {
+            for co in shared.take_runnable(index) {
+                // Only coroutines spawned with `spawn_migratable` are offered.
+                state.push_coroutine_raw(unsafe { co.unwrap() });
+            }
+        }
+
+        if sched.run_once(RUN_BATCH_SIZE) > 0 {
+            shared.offer_runnable(index, &state);
+            continue;
+        }
+
+        if shared.shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+
+        shared.workers[index].has_timers.store(state.has_timers(), Ordering::SeqCst);
+        sched.wait_for_work(|| shared.has_tasks() || shared.shutdown.load(Ordering::SeqCst));
+    }
+
+    CURRENT_WORKER.with(|w| w.set(None));
+
+    // Coroutines still suspended at shutdown cannot be dropped. Leak them
+    // along with the scheduler.
+    if *shared.outstanding.lock().unwrap() > 0 {
+        mem::forget(sched);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::time::Instant;
+    use promise::Promise;
+    use sync::thread_channel;
+    use timer;
+
+    fn pool(num_threads: usize) -> ThreadPoolScheduler {
+        ThreadPoolScheduler::new(ThreadPoolConfig {
+            num_threads: num_threads,
+            ..ThreadPoolConfig::default()
+        })
+    }
+
+    #[test]
+    fn spawned_coroutines_should_run_to_completion() {
+        let pool = pool(4);
+        let count = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..1000 {
+            let count = count.clone();
+            pool.spawn(move |c| {
+                for _ in 0..3 {
+                    c.yield_now(&Promise::new_started());
+                }
+                count.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        pool.wait_idle();
+        assert_eq!(count.load(Ordering::SeqCst), 1000);
+        pool.join();
+    }
+
+    #[test]
+    fn idle_workers_should_steal_work() {
+        let pool = pool(4);
+        let handle = pool.handle();
+        let threads: Arc<Mutex<HashSet<thread::ThreadId>>> = Arc::new(Mutex::new(HashSet::new()));
+
+        let threads2 = threads.clone();
+        pool.spawn(move |_| {
+            // All queued on this worker, which is then kept busy.
+            for _ in 0..64 {
+                let threads = threads2.clone();
+                handle.spawn(move |_| {
+                    threads.lock().unwrap().insert(thread::current().id());
+                    thread::sleep(Duration::from_millis(2));
+                });
+            }
+        });
+        pool.join();
+
+        assert!(threads.lock().unwrap().len() > 1);
+    }
+
+    #[test]
+    fn idle_workers_should_steal_started_coroutines() {
+        let pool = pool(2);
+        let handle = pool.handle();
+        let moved = Arc::new(AtomicUsize::new(0));
+
+        let moved2 = moved.clone();
+        unsafe {
+            pool.spawn_migratable(move |_| {
+                for i in 0..8 {
+                    let moved = moved2.clone();
+                    handle.spawn_migratable(move |c| {
+                        let first = thread::current().id();
+                        for _ in 0..(i + 1) * 10 {
+                            thread::sleep(Duration::from_millis(1));
+                            c.yield_now(&Promise::new_started());
+                        }
+                        if thread::current().id() != first {
+                            moved.fetch_add(1, Ordering::SeqCst);
+                        }
+                    });
+                }
+            });
+        }
+        pool.join();
+
+        assert!(moved.load(Ordering::SeqCst) > 0);
+    }
+
+    #[test]
+    fn dropping_should_not_wait_for_parked_coroutines() {
+        let pool = pool(2);
+        let (tx, rx) = thread_channel::channel::<()>();
+        let started = Arc::new(AtomicBool::new(false));
+
+        let started2 = started.clone();
+        pool.spawn(move |c| {
+            started2.store(true, Ordering::SeqCst);
+            let _ = rx.recv(c);
+        });
+        while !started.load(Ordering::SeqCst) {
+            thread::yield_now();
+        }
+
+        let begin = Instant::now();
+        drop(pool);
+        assert!(begin.elapsed() < Duration::from_secs(1));
+
+        // The parked coroutine is leaked, so waking it up has no effect.
+        drop(tx);
+    }
+
+    #[test]
+    fn spawning_after_shutdown_should_fail() {
+        let pool = pool(1);
+        let handle = pool.handle();
+        drop(pool);
+
+        assert_eq!(handle.try_spawn(|_| {}), Err(SpawnError));
+        assert_eq!(*handle.shared.outstanding.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn coroutines_should_be_able_to_wait() {
+        let pool = pool(2);
+        let handle = pool.handle();
+        let count = Arc::new(AtomicUsize::new(0));
+
+        let count2 = count.clone();
+        pool.spawn(move |c| {
+            timer::sleep(c, Duration::from_millis(20));
+            for _ in 0..8 {
+                let count = count2.clone();
+                handle.spawn(move |c| {
+                    timer::sleep(c, Duration::from_millis(10));
+                    count.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        });
+
+        // Dropping waits for the nested coroutines as well.
+        drop(pool);
+        assert_eq!(count.load(Ordering::SeqCst), 8);
+    }
+}
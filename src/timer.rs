@@ -0,0 +1,118 @@
+use std::cmp::Ordering;
+use std::time::{Duration, Instant};
+use co::Yieldable;
+use mpsc_queue::CoBox;
+use promise::Promise;
+
+/// A coroutine waiting for a deadline.
+pub(crate) struct Timer {
+    pub(crate) deadline: Instant,
+
+    // Keeps timers with the same deadline in FIFO order.
+    pub(crate) seq: u64,
+
+    pub(crate) co: CoBox
+}
+
+impl PartialEq for Timer {
+    fn eq(&self, other: &Timer) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Timer {}
+
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Timer) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Timer {
+    // Reversed, since `BinaryHeap` is a max-heap.
+    fn cmp(&self, other: &Timer) -> Ordering {
+        (other.deadline, other.seq).cmp(&(self.deadline, self.seq))
+    }
+}
+
+/// Returns a promise that resolves at `deadline`.
+pub fn deadline_promise(deadline: Instant) -> Promise {
+    Promise::new(move |cb| cb.notify_at(deadline))
+}
+
+/// Suspends the current coroutine for `dur`.
+pub fn sleep(c: &mut Yieldable, dur: Duration) {
+    sleep_until(c, Instant::now() + dur);
+}
+
+/// Suspends the current coroutine until `deadline`.
+pub fn sleep_until(c: &mut Yieldable, deadline: Instant) {
+    c.yield_now(&deadline_promise(deadline));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use scheduler::Scheduler;
+
+    #[test]
+    fn timers_should_fire_in_deadline_order() {
+        let mut sched = Scheduler::new_default();
+        let state = sched.get_state();
+        let order: Rc<RefCell<Vec<u64>>> = Rc::new(RefCell::new(Vec::new()));
+
+        for &ms in [30u64, 10, 20, 10].iter() {
+            let order = order.clone();
+            state.start_coroutine(move |c| {
+                sleep(c, Duration::from_millis(ms));
+                order.borrow_mut().push(ms);
+            });
+        }
+
+        let vp = state.prepare_coroutine(|c| {
+            sleep(c, Duration::from_millis(50));
+        });
+        sched.run_value_promise_to_end(vp).unwrap();
+        assert_eq!(*order.borrow(), vec![10, 10, 20, 30]);
+    }
+
+    #[test]
+    fn pending_timers_should_be_drained_on_shutdown() {
+        let mut sched = Scheduler::new_default();
+        let state = sched.get_state();
+        let woken: Rc<RefCell<bool>> = Rc::new(RefCell::new(false));
+
+        let woken2 = woken.clone();
+        state.start_coroutine(move |c| {
+            sleep(c, Duration::from_millis(100));
+            *woken2.borrow_mut() = true;
+        });
+
+        let vp = state.prepare_coroutine(|_| 42);
+        assert_eq!(sched.run_value_promise_to_end(vp).unwrap(), 42);
+        assert!(*woken.borrow());
+        drop(sched);
+    }
+
+    #[test]
+    fn sleep_should_wait_for_deadline() {
+        let mut sched = Scheduler::new_default();
+        let state = sched.get_state();
+
+        let begin = Instant::now();
+        let vp = state.prepare_coroutine(|c| {
+            sleep(c, Duration::from_millis(20));
+            sleep_until(c, Instant::now() + Duration::from_millis(20));
+
+            // Deadlines in the past resolve on the next round.
+            sleep_until(c, Instant::now() - Duration::from_millis(1));
+        });
+        sched.run_value_promise_to_end(vp).unwrap();
+
+        let elapsed = begin.elapsed();
+        assert!(elapsed >= Duration::from_millis(40));
+        assert!(elapsed < Duration::from_secs(1));
+    }
+}